@@ -1,3 +1,4 @@
+import { createHmac } from 'crypto';
 import type { NextApiRequest, NextApiResponse } from 'next';
 import { TTestResponse } from '@agorapp-dao/editor-common/src/types/TTestResponse';
 import { TTestRequest } from '@agorapp-dao/editor-common/src/types/TTestRequest';
@@ -34,21 +35,55 @@ async function post(req: NextApiRequest, res: NextApiResponse<TTestResponse>) {
 
   let baseUrl = getRunnerUrl(body.runner);
 
-  const response = await fetch(`${baseUrl}/v1/solve`, {
+  const path = '/v1/solve';
+  const payload = JSON.stringify(body);
+  const response = await fetch(`${baseUrl}${path}`, {
     method: 'POST',
     headers: {
       'Content-Type': 'application/json',
+      ...runnerAuthHeaders('POST', path, payload),
     },
-    body: JSON.stringify(body),
+    body: payload,
   });
 
+  const text = await response.text();
   if (!response.ok) {
-    const text = await response.text();
     console.error(`Request to ${response.url} failed with ${response.status}`);
     console.error(text);
+    // the runner explains expected failures (compile errors, unknown lesson, ...) with an `errorCode`
+    const json = parseJson(text);
+    if (json?.errorCode) {
+      res.status(response.status).json(json);
+      return;
+    }
     throw new Error(`Request failed`);
   }
 
-  const json = await response.json();
+  const json = JSON.parse(text);
   res.status(200).json(json);
 }
+
+function parseJson(text: string): TTestResponse | undefined {
+  try {
+    return JSON.parse(text);
+  } catch (e) {
+    return undefined;
+  }
+}
+
+/**
+ * Credentials of runners that require authentication: a signature with the shared secret, which covers the method,
+ * path and body of the request, or else the pre-shared token
+ */
+function runnerAuthHeaders(method: string, path: string, body: string): Record<string, string> {
+  const secret = process.env.RUNNER_HMAC_SECRET;
+  if (secret) {
+    const timestamp = Math.floor(Date.now() / 1000).toString();
+    const signature = createHmac('sha256', secret)
+      .update(`${timestamp}.${method}.${path}.${body}`)
+      .digest('hex');
+    return { 'X-Agora-Timestamp': timestamp, 'X-Agora-Signature': signature };
+  }
+  const token = process.env.RUNNER_TOKEN;
+  return token ? { Authorization: `Bearer ${token}` } : {};
+}
@@ -0,0 +1,61 @@
+import { TDiagnostic, TTestErrorCode } from './TTestResponse';
+import { TTestRequest } from './TTestRequest';
+
+/** Transactions to run against the submitted program, to see what it does; nothing is graded */
+export type TSandboxRequest = TTestRequest & {
+  /** Base58 address of the program; by default the `declare_id!` of the submitted code, or the one of the lesson */
+  programId?: string;
+  /** Accounts that exist before the first transaction, by name */
+  accounts?: Record<string, TAccountSpec>;
+  transactions: TTransactionSpec[];
+};
+
+export type TAccountSpec = {
+  pubkey?: string;
+  lamports?: number;
+  /** Name or base58 address; the system program by default */
+  owner?: string;
+  /** Hex */
+  data?: string;
+  /** Size of zeroed data, instead of `data` */
+  space?: number;
+  executable?: boolean;
+  signer?: boolean;
+};
+
+export type TTransactionSpec = {
+  instructions: {
+    /** The lesson program by default */
+    program?: string;
+    accounts?: { account: string; signer?: boolean; writable?: boolean }[];
+    /** Hex */
+    data?: string;
+  }[];
+};
+
+export type TSandboxResponse = {
+  errorCode?: TTestErrorCode;
+  error?: string;
+  diagnostics?: TDiagnostic[];
+  /** Base58 addresses of the named accounts, including `program`, `payer` and `system` */
+  addresses: Record<string, string>;
+  transactions: TTransactionOutcome[];
+  /** State after the last transaction; `null` when the account does not exist */
+  accounts: Record<string, TAccountState | null>;
+  retryAfter?: number;
+};
+
+export type TTransactionOutcome = {
+  error?: string | null;
+  logs: string[];
+  computeUnits?: number | null;
+  accounts: Record<string, { before: TAccountState | null; after: TAccountState | null }>;
+};
+
+export type TAccountState = {
+  lamports: number;
+  owner: string;
+  /** Hex */
+  data: string;
+  executable: boolean;
+};
@@ -1,12 +1,72 @@
 export type TTestResponse = {
   passed: boolean;
+  errorCode?: TTestErrorCode;
   error?: string;
   gas?: number;
   tests: TTest[];
+  diagnostics?: TDiagnostic[];
+  executionMode?: 'native' | 'sbf';
+  /** The toolchain that built and tested the program */
+  toolchain?: TToolchain;
+  cached?: boolean;
+  /** Seconds to wait before submitting again, when `rateLimited` or `quotaExceeded` */
+  retryAfter?: number;
 };
 
+export type TToolchain = {
+  name: string;
+  /** Versions by tool, e.g. `platform-tools` → `v1.41` */
+  versions: Record<string, string>;
+};
+
+export type TTestErrorCode =
+  | 'unauthorized'
+  | 'forbidden'
+  | 'unknownLesson'
+  | 'invalidSubmission'
+  | 'compileError'
+  | 'testCompileError'
+  | 'testFailure'
+  | 'timeout'
+  | 'runnerBusy'
+  | 'runnerRestarting'
+  | 'rateLimited'
+  | 'quotaExceeded'
+  | 'notSupported'
+  | 'internalError';
+
 export type TTest = {
   title: string;
   passed: boolean;
   error?: string;
+  /**
+   * How the accounts used by the test changed; only lessons graded with scenarios (`scenarios.json`) report it,
+   * lessons with Rust `lesson_tests` never do
+   */
+  accounts?: TAccountDiff[];
+};
+
+export type TAccountDiff = {
+  name: string;
+  address: string;
+  changes: TFieldChange[];
+};
+
+/** E.g. `lamports`, `owner`, `dataLen`, `data[8..16]`, or a decoded field like `token.amount` */
+export type TFieldChange = {
+  field: string;
+  /** Missing when the account did not exist */
+  before?: string;
+  after?: string;
+  expected?: string;
+};
+
+export type TDiagnostic = {
+  severity: string;
+  message: string;
+  file?: string;
+  line?: number;
+  column?: number;
+  /** Name of the lint, for warnings of the static analysis, e.g. `missing-signer-check` */
+  code?: string;
 };
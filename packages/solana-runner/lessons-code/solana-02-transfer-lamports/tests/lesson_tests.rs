@@ -53,3 +53,51 @@ async fn test_lamport_transfer() {
     transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
 }
+
+/// Hidden check (see `lesson.json`): all lamports of the source account must end up in the destination.
+#[tokio::test]
+async fn test_lamport_balances() {
+    let program_id = Pubkey::from_str("TransferLamports111111111111111111111111111").unwrap();
+    let source_pubkey = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "solana_lesson_transfer_lamports",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_account(
+        source_pubkey,
+        Account {
+            lamports: 5,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        destination_pubkey,
+        Account {
+            lamports: 890_875,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &(),
+            vec![
+                AccountMeta::new(source_pubkey, false),
+                AccountMeta::new(destination_pubkey, false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let source_lamports = banks_client.get_balance(source_pubkey).await.unwrap();
+    let destination_lamports = banks_client.get_balance(destination_pubkey).await.unwrap();
+    assert_eq!(source_lamports, 0);
+    assert_eq!(destination_lamports, 890_880);
+}
@@ -7,7 +7,6 @@ use {
     },
     solana_program_test::*,
     solana_sdk::{signature::Signer, transaction::Transaction},
-    solana_lesson_sysvar::process_instruction,
 };
 
 #[tokio::test]
@@ -16,7 +15,8 @@ async fn test_sysvar() {
     let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
         "solana_lesson_sysvar",
         program_id,
-        processor!(process_instruction),
+        // the tests are built once, and load the submitted program from `solana_lesson_sysvar.so`
+        None,
     )
         .start()
         .await;
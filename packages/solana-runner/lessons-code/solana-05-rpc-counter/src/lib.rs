@@ -0,0 +1 @@
+//PLACEHOLDER
@@ -0,0 +1,84 @@
+//! Client-side checks, sent over RPC to a local validator that runs the submitted program
+//!
+//! The runner starts the validator with the program deployed, and tells where in `AGORA_RPC_URL` and
+//! `AGORA_PROGRAM_ID`. The expected program keeps a little-endian `u64` counter in the first 8 bytes of the account
+//! it gets, and increments it on every instruction.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction,
+    transaction::Transaction,
+};
+
+fn client() -> (RpcClient, Pubkey) {
+    let url = std::env::var("AGORA_RPC_URL").expect("AGORA_RPC_URL is set by the runner");
+    let program_id = std::env::var("AGORA_PROGRAM_ID").expect("AGORA_PROGRAM_ID is set by the runner");
+    (RpcClient::new_with_commitment(url, CommitmentConfig::confirmed()), Pubkey::from_str(&program_id).unwrap())
+}
+
+fn funded_payer(client: &RpcClient) -> Keypair {
+    let payer = Keypair::new();
+    let signature = client.request_airdrop(&payer.pubkey(), 1_000_000_000).unwrap();
+    while !client.confirm_transaction(&signature).unwrap() {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    payer
+}
+
+fn send(client: &RpcClient, payer: &Keypair, instructions: &[Instruction], signers: &[&Keypair]) {
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    let blockhash = client.get_latest_blockhash().unwrap();
+    let transaction = Transaction::new_signed_with_payer(instructions, Some(&payer.pubkey()), &all_signers[..], blockhash);
+    client.send_and_confirm_transaction(&transaction).unwrap();
+}
+
+/// A new counter account, owned by the program
+fn create_counter(client: &RpcClient, payer: &Keypair, program_id: &Pubkey) -> Keypair {
+    let counter = Keypair::new();
+    let lamports = client.get_minimum_balance_for_rent_exemption(8).unwrap();
+    let create = system_instruction::create_account(&payer.pubkey(), &counter.pubkey(), lamports, 8, program_id);
+    send(client, payer, &[create], &[&counter]);
+    counter
+}
+
+/// The data only makes the transactions differ, so that a repeated increment is not rejected as a duplicate
+fn increment(program_id: &Pubkey, counter: &Pubkey, nonce: u8) -> Instruction {
+    Instruction::new_with_bytes(*program_id, &[nonce], vec![AccountMeta::new(*counter, false)])
+}
+
+fn count(client: &RpcClient, counter: &Pubkey) -> u64 {
+    let data = client.get_account_data(counter).unwrap();
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+#[test]
+fn test_program_is_deployed() {
+    let (client, program_id) = client();
+    let program = client.get_account(&program_id).unwrap();
+    assert!(program.executable, "The program is not executable");
+}
+
+#[test]
+fn test_increment_across_slots() {
+    let (client, program_id) = client();
+    let payer = funded_payer(&client);
+    let counter = create_counter(&client, &payer, &program_id);
+
+    send(&client, &payer, &[increment(&program_id, &counter.pubkey(), 1)], &[]);
+    assert_eq!(count(&client, &counter.pubkey()), 1, "The counter was not incremented");
+
+    let slot = client.get_slot().unwrap();
+    while client.get_slot().unwrap() <= slot {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    send(&client, &payer, &[increment(&program_id, &counter.pubkey(), 2)], &[]);
+    assert_eq!(count(&client, &counter.pubkey()), 2, "The counter was not incremented in a later slot");
+}
@@ -0,0 +1,49 @@
+use {
+    anchor_lang::{AccountDeserialize, InstructionData, ToAccountMetas},
+    solana_lesson_anchor_counter::{accounts, instruction, Counter, ID},
+    solana_program_test::*,
+    solana_sdk::{
+        instruction::Instruction,
+        signature::{Keypair, Signer},
+        system_program,
+        transaction::Transaction,
+    },
+};
+
+/// Anchor entrypoints do not fit `processor!`, so the program is loaded from the `.so` produced by `anchor build`.
+#[tokio::test]
+async fn test_increment() {
+    let program_test = ProgramTest::new("solana_lesson_anchor_counter", ID, None);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let counter = Keypair::new();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction {
+                program_id: ID,
+                accounts: accounts::Initialize {
+                    counter: counter.pubkey(),
+                    user: payer.pubkey(),
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: instruction::Initialize {}.data(),
+            },
+            Instruction {
+                program_id: ID,
+                accounts: accounts::Increment {
+                    counter: counter.pubkey(),
+                }
+                .to_account_metas(None),
+                data: instruction::Increment {}.data(),
+            },
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &counter], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter.pubkey()).await.unwrap().unwrap();
+    let counter = Counter::try_deserialize(&mut account.data.as_slice()).unwrap();
+    assert_eq!(counter.count, 1);
+}
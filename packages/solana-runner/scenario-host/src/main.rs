@@ -0,0 +1,289 @@
+//! Runs the scenarios of a lesson in `solana-program-test`, and prints what happened as JSON
+//!
+//! Usage: `agorapp-solana-scenarios <job.json>`, with the compiled program in `SBF_OUT_DIR`. The job and the outcomes
+//! are described in `src/scenario.rs` of the runner; comparing the outcomes with the expectations is up to the runner.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use solana_program_test::{BanksClient, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_program,
+    transaction::Transaction,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    program_name: String,
+    program_id: String,
+    scenarios: Vec<Scenario>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Scenario {
+    name: String,
+    #[serde(default)]
+    accounts: BTreeMap<String, AccountSpec>,
+    transactions: Vec<TransactionSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountSpec {
+    pubkey: Option<String>,
+    #[serde(default)]
+    lamports: u64,
+    owner: Option<String>,
+    #[serde(default)]
+    data: Hex,
+    space: Option<usize>,
+    #[serde(default)]
+    executable: bool,
+    #[serde(default)]
+    signer: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionSpec {
+    instructions: Vec<InstructionSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstructionSpec {
+    program: Option<String>,
+    #[serde(default)]
+    accounts: Vec<AccountMetaSpec>,
+    #[serde(default)]
+    data: Hex,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountMetaSpec {
+    account: String,
+    #[serde(default)]
+    signer: bool,
+    #[serde(default)]
+    writable: bool,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Outcome {
+    name: String,
+    error: Option<String>,
+    addresses: BTreeMap<String, String>,
+    transactions: Vec<TransactionOutcome>,
+    accounts: BTreeMap<String, Option<AccountState>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionOutcome {
+    error: Option<String>,
+    logs: Vec<String>,
+    compute_units: Option<u64>,
+    /// Accounts of the instructions and the payer, by name (or address, when unnamed)
+    accounts: BTreeMap<String, AccountChange>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountChange {
+    before: Option<AccountState>,
+    after: Option<AccountState>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountState {
+    lamports: u64,
+    owner: String,
+    data: Hex,
+    executable: bool,
+}
+
+impl From<Account> for AccountState {
+    fn from(account: Account) -> Self {
+        Self { lamports: account.lamports, owner: account.owner.to_string(), data: Hex(account.data), executable: account.executable }
+    }
+}
+
+#[derive(Default)]
+struct Hex(Vec<u8>);
+
+impl Serialize for Hex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex::decode(hex.trim_start_matches("0x")).map(Hex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let path = std::env::args().nth(1).ok_or("Usage: agorapp-solana-scenarios <job.json>")?;
+    let job: Job = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let program_id = Pubkey::from_str(&job.program_id)?;
+    let mut outcomes = Vec::new();
+    for scenario in &job.scenarios {
+        let outcome = match run(&job.program_name, program_id, scenario).await {
+            Ok(outcome) => outcome,
+            Err(err) => Outcome { name: scenario.name.clone(), error: Some(err.to_string()), ..Default::default() },
+        };
+        outcomes.push(outcome);
+    }
+    println!("{}", serde_json::to_string(&outcomes)?);
+    Ok(())
+}
+
+/// Addresses by account name, and the keypairs of the accounts that sign
+struct Names {
+    addresses: BTreeMap<String, Pubkey>,
+    keypairs: HashMap<Pubkey, Keypair>,
+}
+
+impl Names {
+    fn resolve(&self, name: &str) -> Result<Pubkey, Box<dyn Error>> {
+        match self.addresses.get(name) {
+            Some(address) => Ok(*address),
+            None => Ok(Pubkey::from_str(name).map_err(|_| format!("Unknown account {name:?}"))?),
+        }
+    }
+
+    fn name(&self, address: &Pubkey) -> String {
+        self.addresses.iter()
+            .find(|(_, named)| *named == address)
+            .map_or_else(|| address.to_string(), |(name, _)| name.clone())
+    }
+}
+
+async fn run(program_name: &str, program_id: Pubkey, scenario: &Scenario) -> Result<Outcome, Box<dyn Error>> {
+    let mut program_test = ProgramTest::default();
+    program_test.prefer_bpf(true);
+    program_test.add_program(program_name, program_id, None);
+
+    let mut names = Names { addresses: BTreeMap::new(), keypairs: HashMap::new() };
+    names.addresses.insert("program".to_string(), program_id);
+    names.addresses.insert("system".to_string(), system_program::id());
+    // all addresses first, since owners may be other accounts
+    for (name, spec) in &scenario.accounts {
+        let address = match (&spec.pubkey, spec.signer) {
+            (Some(pubkey), _) => Pubkey::from_str(pubkey)?,
+            (None, true) => {
+                let keypair = Keypair::new();
+                let address = keypair.pubkey();
+                names.keypairs.insert(address, keypair);
+                address
+            }
+            (None, false) => Pubkey::new_unique(),
+        };
+        names.addresses.insert(name.clone(), address);
+    }
+    for (name, spec) in &scenario.accounts {
+        let data = match spec.space {
+            Some(space) => vec![0; space],
+            None => spec.data.0.clone(),
+        };
+        if spec.lamports == 0 && data.is_empty() {
+            continue;
+        }
+        let owner = match &spec.owner {
+            Some(owner) => names.resolve(owner)?,
+            None => system_program::id(),
+        };
+        let account = Account { lamports: spec.lamports, data, owner, executable: spec.executable, rent_epoch: 0 };
+        program_test.add_account(names.addresses[name], account);
+    }
+
+    let (mut banks_client, payer, _) = program_test.start().await;
+    names.addresses.insert("payer".to_string(), payer.pubkey());
+    let mut transactions = Vec::new();
+    let mut blockhash = Hash::default();
+    for transaction in &scenario.transactions {
+        transactions.push(send(&mut banks_client, &payer, &names, program_id, transaction, &mut blockhash).await?);
+    }
+
+    let mut accounts = BTreeMap::new();
+    for name in scenario.accounts.keys().chain(std::iter::once(&"payer".to_string())) {
+        let account = banks_client.get_account(names.addresses[name]).await?;
+        accounts.insert(name.clone(), account.map(AccountState::from));
+    }
+    let addresses = names.addresses.iter()
+        .map(|(name, address)| (name.clone(), address.to_string()))
+        .collect();
+    Ok(Outcome { name: scenario.name.clone(), error: None, addresses, transactions, accounts })
+}
+
+async fn send(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    names: &Names,
+    program_id: Pubkey,
+    transaction: &TransactionSpec,
+    blockhash: &mut Hash,
+) -> Result<TransactionOutcome, Box<dyn Error>> {
+    let mut instructions = Vec::new();
+    let mut signers = vec![payer];
+    // the payer's lamports change with the fee of every transaction
+    let mut touched = vec![payer.pubkey()];
+    for instruction in &transaction.instructions {
+        let mut accounts = Vec::new();
+        for meta in &instruction.accounts {
+            let pubkey = names.resolve(&meta.account)?;
+            if meta.signer && pubkey != payer.pubkey() {
+                let keypair = names.keypairs.get(&pubkey)
+                    .ok_or_else(|| format!("Account {:?} cannot sign; declare it with \"signer\": true", meta.account))?;
+                if !signers.iter().any(|signer| signer.pubkey() == pubkey) {
+                    signers.push(keypair);
+                }
+            }
+            if !touched.contains(&pubkey) {
+                touched.push(pubkey);
+            }
+            accounts.push(AccountMeta { pubkey, is_signer: meta.signer, is_writable: meta.writable });
+        }
+        let program = match &instruction.program {
+            Some(program) => names.resolve(program)?,
+            None => program_id,
+        };
+        instructions.push(Instruction { program_id: program, accounts, data: instruction.data.0.clone() });
+    }
+    let mut before = Vec::new();
+    for pubkey in &touched {
+        before.push(banks_client.get_account(*pubkey).await?);
+    }
+    // a transaction repeating the previous one would have the same signature, and be rejected as already processed;
+    // so each one waits for a blockhash of its own
+    *blockhash = banks_client.get_new_latest_blockhash(blockhash).await?;
+    let transaction = Transaction::new_signed_with_payer(&instructions, Some(&payer.pubkey()), &signers[..], *blockhash);
+    let processed = banks_client.process_transaction_with_metadata(transaction).await?;
+    let mut accounts = BTreeMap::new();
+    for (pubkey, before) in touched.iter().zip(before) {
+        let after = banks_client.get_account(*pubkey).await?;
+        let change = AccountChange { before: before.map(AccountState::from), after: after.map(AccountState::from) };
+        accounts.insert(names.name(pubkey), change);
+    }
+    Ok(TransactionOutcome {
+        error: processed.result.err().map(|err| err.to_string()),
+        logs: processed.metadata.as_ref().map(|metadata| metadata.log_messages.clone()).unwrap_or_default(),
+        compute_units: processed.metadata.map(|metadata| metadata.compute_units_consumed),
+        accounts,
+    })
+}
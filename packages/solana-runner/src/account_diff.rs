@@ -0,0 +1,202 @@
+//! How the accounts of a scenario changed, as reported in `TTest::accounts`
+//!
+//! The data of accounts owned by the SPL token programs is decoded, so that a student sees `token.amount: 0 → 100`
+//! rather than changed bytes. The layouts are read by hand, because the runner does not link the Solana crates.
+
+use std::collections::BTreeMap;
+
+use crate::scenario::{AccountState, ExpectedAccount};
+use crate::types::{TAccountDiff, TFieldChange};
+
+/// SPL Token and Token-2022, whose accounts and mints share the base layout
+const TOKEN_PROGRAMS: [&str; 2] = ["TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA", "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb"];
+const MINT_LEN: usize = 82;
+const TOKEN_ACCOUNT_LEN: usize = 165;
+/// Changed byte ranges of data beyond this many are not reported
+const MAX_DATA_RANGES: usize = 8;
+
+/// Changes of the account `name` from `before` to `after` (`None` when it does not exist), with the expectations of
+/// the test; `None` when nothing changed and nothing is expected
+pub fn diff(
+    name: &str,
+    address: &str,
+    before: Option<&AccountState>,
+    after: Option<&AccountState>,
+    expected: Option<&ExpectedAccount>,
+    addresses: &BTreeMap<String, String>,
+) -> Option<TAccountDiff> {
+    let expected = expected.cloned().unwrap_or_default();
+    let named = |address: &str| addresses.iter()
+        .find(|(_, named)| *named == address)
+        .map_or_else(|| address.to_string(), |(name, _)| name.clone());
+    let mut changes = Changes::default();
+
+    if before.is_some() != after.is_some() || expected.closed {
+        changes.push("exists", Some(before.is_some().to_string()), Some(after.is_some().to_string()), expected.closed.then(|| false.to_string()));
+    }
+    changes.push("lamports", before.map(|state| state.lamports.to_string()), after.map(|state| state.lamports.to_string()), expected.lamports.map(|lamports| lamports.to_string()));
+    changes.push("owner", before.map(|state| named(&state.owner)), after.map(|state| named(&state.owner)), expected.owner.clone());
+    let expected_len = expected.data_len.or(expected.data.as_ref().map(|data| data.0.len()));
+    changes.push("dataLen", before.map(|state| state.data.0.len().to_string()), after.map(|state| state.data.0.len().to_string()), expected_len.map(|len| len.to_string()));
+
+    let before_data = before.map_or(&[][..], |state| &state.data.0[..]);
+    let after_data = after.map_or(&[][..], |state| &state.data.0[..]);
+    let decoded = (before.map(decode).unwrap_or_default(), after.map(decode).unwrap_or_default());
+    if let Some(data) = expected.data.as_ref().filter(|data| data.0 != after_data) {
+        changes.push("data", before.map(|_| hex::encode(before_data)), after.map(|_| hex::encode(after_data)), Some(hex::encode(&data.0)));
+    } else if !decoded.0.is_empty() || !decoded.1.is_empty() {
+        let fields = decoded.0.iter().chain(&decoded.1).map(|(field, _)| *field);
+        let mut seen = Vec::new();
+        for field in fields {
+            if seen.contains(&field) {
+                continue;
+            }
+            seen.push(field);
+            let value = |decoded: &Vec<(&str, String)>| decoded.iter()
+                .find(|(decoded_field, _)| *decoded_field == field)
+                .map(|(_, value)| named(value));
+            changes.push(field, value(&decoded.0), value(&decoded.1), None);
+        }
+    } else {
+        for (start, end) in changed_ranges(before_data, after_data).into_iter().take(MAX_DATA_RANGES) {
+            let bytes = |data: &[u8]| hex::encode(&data[start.min(data.len())..end.min(data.len())]);
+            changes.push(&format!("data[{start}..{end}]"), before.map(|_| bytes(before_data)), after.map(|_| bytes(after_data)), None);
+        }
+    }
+
+    let changes = changes.0;
+    (!changes.is_empty()).then(|| TAccountDiff { name: name.to_string(), address: address.to_string(), changes })
+}
+
+#[derive(Default)]
+struct Changes(Vec<TFieldChange>);
+
+impl Changes {
+    /// Add the field, if it changed or a value is expected
+    fn push(&mut self, field: &str, before: Option<String>, after: Option<String>, expected: Option<String>) {
+        if before != after || expected.is_some() {
+            self.0.push(TFieldChange { field: field.to_string(), before, after, expected });
+        }
+    }
+}
+
+/// Byte ranges `start..end` where the data differs, including bytes that only one of them has
+fn changed_ranges(before: &[u8], after: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for offset in 0..before.len().max(after.len()) {
+        if before.get(offset) == after.get(offset) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if *end == offset => *end += 1,
+            _ => ranges.push((offset, offset + 1)),
+        }
+    }
+    ranges
+}
+
+/// Fields of an SPL token account or mint; empty for other accounts
+fn decode(state: &AccountState) -> Vec<(&'static str, String)> {
+    let data = &state.data.0;
+    if !TOKEN_PROGRAMS.contains(&state.owner.as_str()) {
+        return vec![];
+    }
+    // Token-2022 accounts with extensions are longer, and tell their type in the byte after the token account layout
+    let is_mint = match data.len() {
+        MINT_LEN => true,
+        TOKEN_ACCOUNT_LEN => false,
+        len if len > TOKEN_ACCOUNT_LEN && data[TOKEN_ACCOUNT_LEN] == 1 => true,
+        len if len > TOKEN_ACCOUNT_LEN && data[TOKEN_ACCOUNT_LEN] == 2 => false,
+        _ => return vec![],
+    };
+    if is_mint {
+        vec![
+            ("mint.mintAuthority", optional_pubkey(data, 0)),
+            ("mint.supply", u64_at(data, 36).to_string()),
+            ("mint.decimals", data[44].to_string()),
+            ("mint.isInitialized", (data[45] != 0).to_string()),
+            ("mint.freezeAuthority", optional_pubkey(data, 46)),
+        ]
+    } else {
+        let state = match data[108] {
+            0 => "uninitialized",
+            1 => "initialized",
+            2 => "frozen",
+            _ => "invalid",
+        };
+        let is_native = if u32_at(data, 109) == 1 { u64_at(data, 113).to_string() } else { "none".to_string() };
+        vec![
+            ("token.mint", pubkey(data, 0)),
+            ("token.owner", pubkey(data, 32)),
+            ("token.amount", u64_at(data, 64).to_string()),
+            ("token.delegate", optional_pubkey(data, 72)),
+            ("token.state", state.to_string()),
+            ("token.isNative", is_native),
+            ("token.delegatedAmount", u64_at(data, 121).to_string()),
+            ("token.closeAuthority", optional_pubkey(data, 129)),
+        ]
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn pubkey(data: &[u8], offset: usize) -> String {
+    bs58::encode(&data[offset..offset + 32]).into_string()
+}
+
+/// A `COption<Pubkey>`: a 4 byte tag, then the address
+fn optional_pubkey(data: &[u8], offset: usize) -> String {
+    if u32_at(data, offset) == 1 { pubkey(data, offset + 4) } else { "none".to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::Hex;
+
+    const SYSTEM: &str = "11111111111111111111111111111111";
+
+    fn state(lamports: u64, owner: &str, data: Vec<u8>) -> AccountState {
+        AccountState { lamports, owner: owner.to_string(), data: Hex(data), executable: false }
+    }
+
+    #[test]
+    fn diff_lamports_and_data() {
+        let addresses = BTreeMap::from([("system".to_string(), SYSTEM.to_string())]);
+        let expected = ExpectedAccount { lamports: Some(0), ..Default::default() };
+        let before = state(5, SYSTEM, vec![0, 1, 2, 3]);
+        let after = state(5, SYSTEM, vec![0, 9, 9, 3, 4]);
+        let diff = diff("source", "Source1111111111111111111111111111111111111", Some(&before), Some(&after), Some(&expected), &addresses).unwrap();
+        let changes = diff.changes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(changes, ["lamports: 5 → 5 (expected 0)", "dataLen: 4 → 5", "data[1..3]: 0102 → 0909", "data[4..5]:  → 04"]);
+
+        assert!(super::diff("source", "Source1111111111111111111111111111111111111", Some(&before), Some(&before), None, &addresses).is_none());
+        let diff = super::diff("source", "Source1111111111111111111111111111111111111", Some(&before), None, None, &addresses).unwrap();
+        assert_eq!(diff.changes[0].to_string(), "exists: true → false");
+    }
+
+    #[test]
+    fn decode_token_accounts() {
+        let mut data = vec![0; TOKEN_ACCOUNT_LEN];
+        data[108] = 1;
+        let before = state(2039280, TOKEN_PROGRAMS[0], data.clone());
+        data[64..72].copy_from_slice(&100u64.to_le_bytes());
+        let after = state(2039280, TOKEN_PROGRAMS[0], data);
+        let diff = diff("vault", "Vau1t11111111111111111111111111111111111111", Some(&before), Some(&after), None, &BTreeMap::new()).unwrap();
+        let changes = diff.changes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(changes, ["token.amount: 0 → 100"]);
+
+        let mut mint = vec![0; MINT_LEN];
+        mint[44] = 9;
+        mint[45] = 1;
+        let decoded = decode(&state(1461600, TOKEN_PROGRAMS[1], mint));
+        assert!(decoded.contains(&("mint.decimals", "9".to_string())));
+        assert!(decoded.contains(&("mint.mintAuthority", "none".to_string())));
+    }
+}
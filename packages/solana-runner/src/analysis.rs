@@ -0,0 +1,472 @@
+//! Static analysis of the submitted code, warning about the mistakes behind most real-world Solana exploits
+//!
+//! The lints are heuristics over the syntax of each function, without type information: they follow the accounts
+//! bound from `next_account_info` (or an `&[AccountInfo]` parameter) and the instruction data (an `&[u8]` parameter)
+//! by name. Anchor checks signers and owners through its account types, so only the other lints apply to its lessons.
+//! The warnings are reported as diagnostics; they fail the submission only when the lesson denies them
+//! (see `LessonMeta::lints`).
+
+use std::collections::HashSet;
+
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+
+use crate::lesson::LessonKind;
+use crate::types::{TDiagnostic, TEditorFile};
+
+/// Title of the failing test added when the lesson denies the warnings
+pub const TEST_TITLE: &str = "static analysis";
+
+const MISSING_SIGNER_CHECK: &str = "missing-signer-check";
+const MISSING_OWNER_CHECK: &str = "missing-owner-check";
+const UNCHECKED_LAMPORTS_ARITHMETIC: &str = "unchecked-lamports-arithmetic";
+const PDA_BUMP_FROM_INPUT: &str = "pda-bump-from-input";
+const PDA_SEED_FROM_INPUT: &str = "pda-seed-from-input";
+
+/// How deeply the parser may have to recurse into a file for it to be analyzed, see `nested_deeper_than`
+const MAX_NESTING: usize = 1000;
+
+/// Stack of the thread that parses and visits the files; the deepest files allowed take less than 16MB in a debug
+/// build
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Warnings about the submitted Rust files; files that do not parse are left to the compiler, and so are files
+/// nested too deeply for the parser, which recurses and would overflow the stack
+pub fn analyze(files: &[TEditorFile], kind: LessonKind) -> Vec<TDiagnostic> {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .name("analysis".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || analyze_files(files, kind))
+            .expect("Cannot start the static analysis")
+            .join()
+            .unwrap_or_default()
+    })
+}
+
+fn analyze_files(files: &[TEditorFile], kind: LessonKind) -> Vec<TDiagnostic> {
+    let mut diagnostics = Vec::new();
+    for file in files.iter().filter(|file| file.path.ends_with(".rs")) {
+        let Ok(tokens) = file.content.parse::<proc_macro2::TokenStream>() else {
+            continue;
+        };
+        if nested_deeper_than(tokens.clone(), MAX_NESTING) {
+            continue;
+        }
+        let Ok(syntax) = syn::parse2::<syn::File>(tokens) else {
+            continue;
+        };
+        let mut lints = Lints { kind, warnings: Vec::new() };
+        lints.visit_file(&syntax);
+        for warning in lints.warnings {
+            let mut diagnostic = TDiagnostic::new("warning", warning.message).with_code(warning.code);
+            diagnostic.set_location(format!("src/{}", file.path), Some(warning.line as u32), Some(warning.column as u32 + 1));
+            diagnostics.push(diagnostic);
+        }
+    }
+    diagnostics
+}
+
+/// Whether the parser could recurse more than `limit` levels into the tokens: every open delimiter counts, and so
+/// does every token of the unfinished statement or expression within each of them, as prefix operators, closures,
+/// `else if` chains and generic arguments all nest without delimiters. The lexer and this walk keep their own
+/// stacks, so they do not overflow.
+fn nested_deeper_than(tokens: proc_macro2::TokenStream, limit: usize) -> bool {
+    struct Frame {
+        tokens: proc_macro2::token_stream::IntoIter,
+        /// Tokens since the last `;` or `,`, or since the last block unless it goes on with `else`
+        run: usize,
+        after_block: bool,
+    }
+    let mut stack = vec![Frame { tokens: tokens.into_iter(), run: 0, after_block: false }];
+    let mut depth = 1;
+    while let Some(frame) = stack.last_mut() {
+        let Some(token) = frame.tokens.next() else {
+            depth -= frame.run + 1;
+            stack.pop();
+            continue;
+        };
+        if std::mem::take(&mut frame.after_block) && !matches!(&token, proc_macro2::TokenTree::Ident(ident) if ident == "else") {
+            depth -= frame.run;
+            frame.run = 0;
+        }
+        match token {
+            proc_macro2::TokenTree::Punct(punct) if matches!(punct.as_char(), ';' | ',') => {
+                depth -= frame.run;
+                frame.run = 0;
+            }
+            proc_macro2::TokenTree::Group(group) => {
+                frame.run += 1;
+                frame.after_block = group.delimiter() == proc_macro2::Delimiter::Brace;
+                stack.push(Frame { tokens: group.stream().into_iter(), run: 0, after_block: false });
+                depth += 2;
+            }
+            _ => {
+                frame.run += 1;
+                depth += 1;
+            }
+        }
+        if depth > limit {
+            return true;
+        }
+    }
+    false
+}
+
+struct Warning {
+    code: &'static str,
+    message: String,
+    line: usize,
+    column: usize,
+}
+
+/// Finds the functions of a file, and checks each of them
+struct Lints {
+    kind: LessonKind,
+    warnings: Vec<Warning>,
+}
+
+impl Lints {
+    fn check(&mut self, sig: &syn::Signature, block: &syn::Block) {
+        let mut facts = Facts::default();
+        for input in &sig.inputs {
+            let syn::FnArg::Typed(typed) = input else {
+                continue;
+            };
+            let names = pattern_names(&typed.pat);
+            match parameter_kind(&typed.ty) {
+                Some(Parameter::Accounts) => facts.account_slices.extend(names),
+                Some(Parameter::Account) => facts.accounts.extend(names),
+                Some(Parameter::Input) => facts.input.extend(names),
+                None => {}
+            }
+        }
+        facts.visit_block(block);
+        let mut checks = Checks { facts: &facts, kind: self.kind, warnings: Vec::new(), reported: HashSet::new() };
+        checks.visit_block(block);
+        self.warnings.extend(checks.warnings);
+    }
+}
+
+impl<'ast> Visit<'ast> for Lints {
+    fn visit_item_fn(&mut self, item: &'ast syn::ItemFn) {
+        self.check(&item.sig, &item.block);
+        syn::visit::visit_item_fn(self, item);
+    }
+
+    fn visit_impl_item_fn(&mut self, item: &'ast syn::ImplItemFn) {
+        self.check(&item.sig, &item.block);
+        syn::visit::visit_impl_item_fn(self, item);
+    }
+}
+
+enum Parameter {
+    /// `&[AccountInfo]`
+    Accounts,
+    /// `&AccountInfo`
+    Account,
+    /// `&[u8]`, i.e. instruction data
+    Input,
+}
+
+fn parameter_kind(ty: &syn::Type) -> Option<Parameter> {
+    let syn::Type::Reference(reference) = ty else {
+        return None;
+    };
+    match &*reference.elem {
+        syn::Type::Slice(slice) if is_type(&slice.elem, "AccountInfo") => Some(Parameter::Accounts),
+        syn::Type::Slice(slice) if is_type(&slice.elem, "u8") => Some(Parameter::Input),
+        elem if is_type(elem, "AccountInfo") => Some(Parameter::Account),
+        _ => None,
+    }
+}
+
+/// Whether the type is `name`, with any path and generics, e.g. `AccountInfo<'a>`
+fn is_type(ty: &syn::Type, name: &str) -> bool {
+    matches!(ty, syn::Type::Path(path) if path.path.segments.last().is_some_and(|segment| segment.ident == name))
+}
+
+/// What a function does with its accounts and instruction data, by variable name
+#[derive(Default)]
+struct Facts {
+    accounts: HashSet<String>,
+    account_slices: HashSet<String>,
+    /// The instruction data, and values taken from it
+    input: HashSet<String>,
+    signer_checked: HashSet<String>,
+    owner_checked: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for Facts {
+    fn visit_local(&mut self, local: &'ast syn::Local) {
+        if let Some(init) = &local.init {
+            let names = pattern_names(&local.pat);
+            if calls(&init.expr, "next_account_info") || mentions(&init.expr, &self.account_slices) {
+                self.accounts.extend(names);
+            } else if mentions(&init.expr, &self.input) && !calls(&init.expr, "find_program_address") {
+                // the bump found by `find_program_address` is canonical, whatever the seeds
+                self.input.extend(names);
+            }
+        }
+        syn::visit::visit_local(self, local);
+    }
+
+    fn visit_expr_field(&mut self, field: &'ast syn::ExprField) {
+        if let (syn::Member::Named(member), Some(account)) = (&field.member, root_name(&field.base)) {
+            if member == "is_signer" {
+                self.signer_checked.insert(account);
+            } else if member == "owner" {
+                self.owner_checked.insert(account);
+            }
+        }
+        syn::visit::visit_expr_field(self, field);
+    }
+}
+
+/// Reports the warnings of a function, knowing its `Facts`
+struct Checks<'f> {
+    facts: &'f Facts,
+    kind: LessonKind,
+    warnings: Vec<Warning>,
+    /// Lint codes by account or line, so that each is reported once
+    reported: HashSet<(&'static str, String)>,
+}
+
+impl Checks<'_> {
+    fn warn(&mut self, code: &'static str, key: String, span: proc_macro2::Span, message: String) {
+        if self.reported.insert((code, key)) {
+            let start = span.start();
+            self.warnings.push(Warning { code, message, line: start.line, column: start.column });
+        }
+    }
+
+    /// The account the expression is about, if it is one of the function
+    fn account(&self, expr: &syn::Expr) -> Option<String> {
+        root_name(expr).filter(|name| self.facts.accounts.contains(name))
+    }
+}
+
+impl<'ast> Visit<'ast> for Checks<'_> {
+    fn visit_expr_binary(&mut self, binary: &'ast syn::ExprBinary) {
+        use syn::BinOp;
+        let assigns = matches!(binary.op, BinOp::AddAssign(_) | BinOp::SubAssign(_) | BinOp::MulAssign(_));
+        let computes = matches!(binary.op, BinOp::Add(_) | BinOp::Sub(_) | BinOp::Mul(_));
+        if assigns && touches_lamports(&binary.left) || computes && (touches_lamports(&binary.left) || touches_lamports(&binary.right)) {
+            let line = binary.span().start().line;
+            self.warn(UNCHECKED_LAMPORTS_ARITHMETIC, line.to_string(), binary.span(),
+                "Lamports are changed with unchecked arithmetic, which silently wraps around in on-chain builds; \
+                use `checked_add`/`checked_sub` and return an error on overflow".to_string());
+        }
+        if self.kind == LessonKind::Native && matches!(binary.op, BinOp::SubAssign(_)) && touches_lamports(&binary.left) {
+            if let Some(account) = self.account(&binary.left).filter(|account| !self.facts.signer_checked.contains(account)) {
+                self.warn(MISSING_SIGNER_CHECK, account.clone(), binary.span(), format!(
+                    "Lamports are taken from `{account}` without checking `{account}.is_signer`; \
+                    anyone could pass an account that is not theirs"));
+            }
+        }
+        syn::visit::visit_expr_binary(self, binary);
+    }
+
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        // `x.try_borrow_data()` or `x.data.borrow()`; writes are rejected by the runtime when the program is not the owner
+        let read = match &*call.receiver {
+            syn::Expr::Field(field) if call.method == "borrow" && matches!(&field.member, syn::Member::Named(member) if member == "data") => Some(&*field.base),
+            receiver if call.method == "try_borrow_data" => Some(receiver),
+            _ => None,
+        };
+        let unchecked = read.and_then(|read| self.account(read)).filter(|account| !self.facts.owner_checked.contains(account));
+        if let (LessonKind::Native, Some(account)) = (self.kind, unchecked) {
+            self.warn(MISSING_OWNER_CHECK, account.clone(), call.span(), format!(
+                "The data of `{account}` is read without checking `{account}.owner`; \
+                a caller could pass an account of another program with forged data"));
+        }
+        syn::visit::visit_expr_method_call(self, call);
+    }
+
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        let function = match &*call.func {
+            syn::Expr::Path(path) => path.path.segments.last().map(|segment| segment.ident.to_string()),
+            _ => None,
+        };
+        let seeds_from_input = call.args.first().is_some_and(|seeds| mentions(seeds, &self.facts.input));
+        let line = call.span().start().line.to_string();
+        match function.as_deref() {
+            Some("create_program_address") if seeds_from_input => {
+                self.warn(PDA_BUMP_FROM_INPUT, line, call.span(),
+                    "The seeds of this PDA come from instruction data, so any bump the caller picks is accepted; \
+                    derive the canonical bump with `find_program_address`, or compare it with the stored one".to_string());
+            }
+            Some("find_program_address") if seeds_from_input => {
+                self.warn(PDA_SEED_FROM_INPUT, line, call.span(),
+                    "A seed of this PDA comes from instruction data, so the caller chooses which address is derived; \
+                    validate it, or derive the address from fixed seeds and account keys".to_string());
+            }
+            _ => {}
+        }
+        syn::visit::visit_expr_call(self, call);
+    }
+}
+
+/// Variables bound by a pattern, e.g. both of `(address, bump)`
+fn pattern_names(pat: &syn::Pat) -> Vec<String> {
+    match pat {
+        syn::Pat::Ident(ident) => vec![ident.ident.to_string()],
+        syn::Pat::Type(typed) => pattern_names(&typed.pat),
+        syn::Pat::Reference(reference) => pattern_names(&reference.pat),
+        syn::Pat::Tuple(tuple) => tuple.elems.iter().flat_map(pattern_names).collect(),
+        _ => vec![],
+    }
+}
+
+/// The variable an expression like `**source.try_borrow_mut_lamports()?` or `&accounts[0]` starts from
+fn root_name(expr: &syn::Expr) -> Option<String> {
+    match expr {
+        syn::Expr::Path(path) => path.path.get_ident().map(ToString::to_string),
+        syn::Expr::Unary(unary) => root_name(&unary.expr),
+        syn::Expr::Reference(reference) => root_name(&reference.expr),
+        syn::Expr::Paren(paren) => root_name(&paren.expr),
+        syn::Expr::Try(expr_try) => root_name(&expr_try.expr),
+        syn::Expr::MethodCall(call) => root_name(&call.receiver),
+        syn::Expr::Field(field) => root_name(&field.base),
+        syn::Expr::Index(index) => root_name(&index.expr),
+        _ => None,
+    }
+}
+
+/// Whether the expression uses any of the variables
+fn mentions(expr: &syn::Expr, names: &HashSet<String>) -> bool {
+    struct Mentions<'n> {
+        names: &'n HashSet<String>,
+        found: bool,
+    }
+    impl<'ast> Visit<'ast> for Mentions<'_> {
+        fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+            self.found |= path.path.get_ident().is_some_and(|ident| self.names.contains(&ident.to_string()));
+        }
+    }
+    let mut visitor = Mentions { names, found: false };
+    visitor.visit_expr(expr);
+    visitor.found
+}
+
+/// Whether the expression calls the function, e.g. `next_account_info(iter)?`
+fn calls(expr: &syn::Expr, function: &str) -> bool {
+    struct Calls<'f> {
+        function: &'f str,
+        found: bool,
+    }
+    impl<'ast> Visit<'ast> for Calls<'_> {
+        fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+            if let syn::Expr::Path(path) = &*call.func {
+                self.found |= path.path.segments.last().is_some_and(|segment| segment.ident == self.function);
+            }
+            syn::visit::visit_expr_call(self, call);
+        }
+    }
+    let mut visitor = Calls { function, found: false };
+    visitor.visit_expr(expr);
+    visitor.found
+}
+
+/// Whether the expression reads or writes the lamports of an account, e.g. `source.lamports()`
+fn touches_lamports(expr: &syn::Expr) -> bool {
+    struct Lamports {
+        found: bool,
+    }
+    impl<'ast> Visit<'ast> for Lamports {
+        fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+            self.found |= ["lamports", "try_borrow_lamports", "try_borrow_mut_lamports"].iter().any(|method| call.method == method);
+            syn::visit::visit_expr_method_call(self, call);
+        }
+
+        fn visit_expr_field(&mut self, field: &'ast syn::ExprField) {
+            self.found |= matches!(&field.member, syn::Member::Named(member) if member == "lamports");
+            syn::visit::visit_expr_field(self, field);
+        }
+    }
+    let mut visitor = Lamports { found: false };
+    visitor.visit_expr(expr);
+    visitor.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warnings(content: &str, kind: LessonKind) -> Vec<String> {
+        let files = [TEditorFile { path: "lib.rs".to_string(), content: content.to_string() }];
+        analyze(&files, kind).into_iter()
+            .map(|diagnostic| serde_json::to_value(diagnostic).unwrap())
+            .map(|diagnostic| format!("{}:{}", diagnostic["line"], diagnostic["code"].as_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn unchecked_transfer() {
+        let unchecked = r#"
+            pub fn process_instruction(_program_id: &Pubkey, accounts: &[AccountInfo], _instruction_data: &[u8]) -> ProgramResult {
+                let account_info_iter = &mut accounts.iter();
+                let source_info = next_account_info(account_info_iter)?;
+                let destination_info = next_account_info(account_info_iter)?;
+                **source_info.try_borrow_mut_lamports()? -= 5;
+                **destination_info.try_borrow_mut_lamports()? += 5;
+                let counter = Counter::try_from_slice(&destination_info.data.borrow())?;
+                Ok(())
+            }
+        "#;
+        assert_eq!(warnings(unchecked, LessonKind::Native), [
+            "6:unchecked-lamports-arithmetic", "6:missing-signer-check", "7:unchecked-lamports-arithmetic", "8:missing-owner-check",
+        ]);
+        assert_eq!(warnings(unchecked, LessonKind::Anchor), ["6:unchecked-lamports-arithmetic", "7:unchecked-lamports-arithmetic"]);
+
+        let checked = r#"
+            pub fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], _instruction_data: &[u8]) -> ProgramResult {
+                let source_info = &accounts[0];
+                let destination_info = &accounts[1];
+                if !source_info.is_signer || destination_info.owner != program_id {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                let counter = Counter::try_from_slice(&destination_info.try_borrow_data()?)?;
+                **source_info.try_borrow_mut_lamports()? = source_info.lamports().checked_sub(5).ok_or(ProgramError::InsufficientFunds)?;
+                Ok(())
+            }
+        "#;
+        assert!(warnings(checked, LessonKind::Native).is_empty());
+        assert!(warnings("fn broken( {", LessonKind::Native).is_empty());
+    }
+
+    #[test]
+    fn pda_from_instruction_data() {
+        // the template of the CPI lesson
+        let cpi = r#"
+            pub fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], instruction_data: &[u8]) -> ProgramResult {
+                let seeds = b"You pass butter";
+                let (_derived_address, bump_seed) = Pubkey::find_program_address(&[seeds, &[instruction_data[0]]], program_id);
+                let bump = instruction_data[1];
+                let address = Pubkey::create_program_address(&[seeds, &[bump]], program_id)?;
+                let canonical = Pubkey::create_program_address(&[seeds, &[bump_seed]], program_id)?;
+                Ok(())
+            }
+        "#;
+        assert_eq!(warnings(cpi, LessonKind::Native), ["4:pda-seed-from-input", "6:pda-bump-from-input"]);
+    }
+
+    #[test]
+    fn deeply_nested_submissions() {
+        // the parser recurses, so these would overflow the stack of a server thread
+        for nested in [
+            format!("fn f() {{ let _ = {}1{}; }}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("fn f() {{ let _ = {}1{}; }}", "{".repeat(2000), "}".repeat(2000)),
+            format!("fn f() {{ let _ = {}1; }}", "-".repeat(100_000)),
+            format!("fn f() {{ let _ = {}1; }}", "||".repeat(2000)),
+            format!("fn f() {{ if a {{}} {} }}", "else if a {} ".repeat(2000)),
+            format!("fn f() {{ let x: {}u8{} = 1; }}", "V<".repeat(2000), ">".repeat(2000)),
+        ] {
+            assert!(warnings(&nested, LessonKind::Native).is_empty());
+        }
+
+        let lamports = "**accounts[0].try_borrow_mut_lamports()? -= 5;";
+        let nested = format!("fn f(accounts: &[AccountInfo]) {{ {}{lamports}{} }}", "{".repeat(100), "}".repeat(100));
+        assert_eq!(warnings(&nested, LessonKind::Native), ["1:unchecked-lamports-arithmetic"]);
+    }
+}
@@ -0,0 +1,126 @@
+//! OpenAPI description of the solve protocol, generated from the Rust types
+//!
+//! The generated document is also committed as `openapi.json`, for the TypeScript clients to generate their types from;
+//! a test fails when the two drift apart. Regenerate with `make openapi`.
+
+use utoipa::OpenApi;
+
+use crate::scenario::{AccountChange, AccountMetaSpec, AccountSpec, AccountState, InstructionSpec, TransactionOutcome, TransactionSpec};
+use crate::types::{
+    ErrorCode, ExecutionMode, TAccountDiff, TDiagnostic, TEditorFile, TFieldChange, TSandboxRequest, TSandboxResponse, TTest, TTestRequest,
+    TTestResponse, TToolchain,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "AgorApp Solana runner",
+        description = "Grades Solana lessons by building and testing submitted programs. \
+            `/v1/solve` takes the same requests as `/v2/solve`, but answers `200` with the `errorCode` in the body only.",
+        license(name = "AGPL-3.0"),
+    ),
+    paths(crate::solve, crate::sandbox),
+    components(schemas(
+        TTestRequest, TEditorFile, TTestResponse, TTest, TAccountDiff, TFieldChange, TDiagnostic, ErrorCode, ExecutionMode, TToolchain,
+        TSandboxRequest, TSandboxResponse, AccountSpec, TransactionSpec, InstructionSpec, AccountMetaSpec, TransactionOutcome,
+        AccountChange, AccountState,
+    )),
+)]
+pub struct ApiDoc;
+
+pub fn openapi_json() -> String {
+    ApiDoc::openapi().to_pretty_json().unwrap()
+}
+
+const COMPONENTS_REF: &str = "#/components/schemas/";
+const DEFS_REF: &str = "#/$defs/";
+
+/// JSON schema of one of the protocol types, by its name
+///
+/// The document stands alone: the types it refers to are copied under `$defs`, and the references point there.
+pub fn schema_json(name: &str) -> Option<serde_json::Value> {
+    let schemas = serde_json::to_value(ApiDoc::openapi().components?.schemas).ok()?;
+    let mut schema = schemas.get(name)?.clone();
+    let mut defs = serde_json::Map::new();
+    let mut pending = rewrite_refs(&mut schema);
+    while let Some(referenced) = pending.pop() {
+        if defs.contains_key(&referenced) {
+            continue;
+        }
+        let mut def = schemas.get(&referenced)?.clone();
+        pending.extend(rewrite_refs(&mut def));
+        defs.insert(referenced, def);
+    }
+    if !defs.is_empty() {
+        schema.as_object_mut()?.insert("$defs".to_string(), defs.into());
+    }
+    Some(schema)
+}
+
+/// Point the references to components at `$defs`, returning the names of the referenced schemas
+fn rewrite_refs(value: &mut serde_json::Value) -> Vec<String> {
+    let mut referenced = Vec::new();
+    match value {
+        serde_json::Value::Object(object) => {
+            if let Some(serde_json::Value::String(reference)) = object.get_mut("$ref") {
+                if let Some(name) = reference.strip_prefix(COMPONENTS_REF) {
+                    referenced.push(name.to_string());
+                    *reference = format!("{DEFS_REF}{name}");
+                }
+            }
+            for value in object.values_mut() {
+                referenced.extend(rewrite_refs(value));
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for value in array {
+                referenced.extend(rewrite_refs(value));
+            }
+        }
+        _ => {}
+    }
+    referenced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openapi_is_up_to_date() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("openapi.json");
+        let generated = openapi_json() + "\n";
+        if std::env::var("UPDATE_OPENAPI").is_ok() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let committed = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(committed == generated, "{} is out of date; regenerate it with `make openapi`", path.display());
+    }
+
+    #[test]
+    fn schemas_resolve_their_refs() {
+        let schema = schema_json("TTestResponse").unwrap();
+        let mut refs = Vec::new();
+        collect_refs(&schema, &mut refs);
+        assert!(refs.contains(&"#/$defs/TTest".to_string()), "{refs:?}");
+        assert!(refs.contains(&"#/$defs/TAccountDiff".to_string()), "{refs:?}");
+        for reference in refs {
+            let pointer = reference.strip_prefix('#').unwrap();
+            assert!(schema.pointer(pointer).is_some(), "{reference} does not resolve");
+        }
+        assert!(schema_json("NoSuchType").is_none());
+    }
+
+    fn collect_refs(value: &serde_json::Value, refs: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(object) => {
+                if let Some(reference) = object.get("$ref").and_then(serde_json::Value::as_str) {
+                    refs.push(reference.to_string());
+                }
+                object.values().for_each(|value| collect_refs(value, refs));
+            }
+            serde_json::Value::Array(array) => array.iter().for_each(|value| collect_refs(value, refs)),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Authentication of the requests that start grading
+//!
+//! Every configured method is accepted; with none configured, requests are not authenticated at all.
+//!
+//! * `AGORA_AUTH_TOKEN`: a pre-shared token, sent as `Authorization: Bearer <token>`
+//! * `AGORA_AUTH_HMAC_SECRET`: requests signed by our backend; `x-agora-signature` is the hex HMAC-SHA256
+//!   of `<x-agora-timestamp>.<METHOD>.<path and query>.<body>`, e.g. `1700000000.GET./v1/attempts/42.`, and the
+//!   timestamp (unix seconds) must be recent; a signature is only good for the request it was made for
+//! * `AGORA_AUTH_JWT_SECRET` (HS256) or `AGORA_AUTH_JWT_PUBLIC_KEY` (path to an RS256 PEM key): a JWT issued by
+//!   the platform, sent as `Authorization: Bearer <jwt>`; its `sessionId` and `course` claims are required, and
+//!   restrict the request
+//!
+//! Only our own services, with the pre-shared token or a signature, are not restricted to a session and course.
+
+use anyhow::Context;
+use hmac::Mac;
+use http::{HeaderMap, HeaderName, Method, Uri};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::types::ErrorCode;
+
+static TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("x-agora-timestamp");
+static SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-agora-signature");
+
+/// How far the timestamp of a signed request may be from the current time, to limit replays
+const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+
+pub struct Auth {
+    token: Option<String>,
+    hmac_secret: Option<Vec<u8>>,
+    jwt: Option<(jsonwebtoken::DecodingKey, jsonwebtoken::Validation)>,
+}
+
+/// Who made an authenticated request, and what it is limited to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Principal {
+    /// Session the request belongs to; when set, it takes precedence over the `x-session-id` header
+    pub session_id: Option<String>,
+    /// The only course the request may be for
+    pub course: Option<String>,
+    /// Authenticated with a token the platform issued to a student, rather than by our backend
+    pub student: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Claims {
+    session_id: String,
+    course: String,
+}
+
+#[derive(Debug)]
+pub struct AuthError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl AuthError {
+    fn unauthorized(message: impl ToString) -> Self {
+        Self { code: ErrorCode::Unauthorized, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Auth {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let token = std::env::var("AGORA_AUTH_TOKEN").ok().filter(|token| !token.is_empty());
+        let hmac_secret = std::env::var("AGORA_AUTH_HMAC_SECRET").ok()
+            .filter(|secret| !secret.is_empty())
+            .map(String::into_bytes);
+        let jwt = match (std::env::var("AGORA_AUTH_JWT_SECRET"), std::env::var_os("AGORA_AUTH_JWT_PUBLIC_KEY")) {
+            (Ok(secret), _) if !secret.is_empty() => Some((
+                jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()),
+                jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
+            )),
+            (_, Some(path)) => {
+                let pem = std::fs::read(&path)
+                    .with_context(|| format!("Cannot read JWT public key from {}", path.to_string_lossy()))?;
+                Some((
+                    jsonwebtoken::DecodingKey::from_rsa_pem(&pem).context("Invalid JWT public key")?,
+                    jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256),
+                ))
+            }
+            _ => None,
+        };
+        Ok(Self { token, hmac_secret, jwt })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.token.is_some() || self.hmac_secret.is_some() || self.jwt.is_some()
+    }
+
+    /// Check the credentials of a request; the method, URI and body are needed for signed requests
+    pub fn authenticate(&self, method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Result<Principal, AuthError> {
+        if !self.enabled() {
+            return Ok(Principal::default());
+        }
+        if let Some(secret) = &self.hmac_secret {
+            if headers.contains_key(&SIGNATURE_HEADER) {
+                return verify_signature(secret, method, uri, headers, body, now_secs()).map(|_| Principal::default());
+            }
+        }
+        let bearer = headers.get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| AuthError::unauthorized("Authentication required"))?;
+        if let Some(token) = &self.token {
+            if bool::from(token.as_bytes().ct_eq(bearer.as_bytes())) {
+                return Ok(Principal::default());
+            }
+        }
+        if let Some((key, validation)) = &self.jwt {
+            return match jsonwebtoken::decode::<Claims>(bearer, key, validation) {
+                Ok(token) => Ok(Principal { session_id: Some(token.claims.session_id), course: Some(token.claims.course), student: true }),
+                Err(err) => Err(AuthError::unauthorized(format!("Invalid token: {err}"))),
+            };
+        }
+        Err(AuthError::unauthorized("Invalid token"))
+    }
+}
+
+impl Principal {
+    /// Reject requests for a course the credentials were not issued for
+    pub fn authorize_course(&self, course_slug: &str) -> Result<(), AuthError> {
+        match &self.course {
+            Some(course) if course != course_slug => Err(AuthError {
+                code: ErrorCode::Forbidden,
+                message: format!("Not allowed to submit solutions for course {course_slug:?}"),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject students from the endpoints meant for our own services
+    pub fn authorize_internal(&self) -> Result<(), AuthError> {
+        if self.student {
+            return Err(AuthError { code: ErrorCode::Forbidden, message: "Not allowed".to_string() });
+        }
+        Ok(())
+    }
+
+    /// Reject access to the history of another session
+    pub fn authorize_session(&self, session_id: Option<&str>) -> Result<(), AuthError> {
+        match &self.session_id {
+            Some(own) if Some(own.as_str()) != session_id => Err(AuthError {
+                code: ErrorCode::Forbidden,
+                message: "Not allowed to access another session".to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn verify_signature(secret: &[u8], method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8], now: u64) -> Result<(), AuthError> {
+    let timestamp = headers.get(&TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AuthError::unauthorized(format!("Signed request without {TIMESTAMP_HEADER}")))?;
+    let signed_at: u64 = timestamp.parse()
+        .map_err(|_| AuthError::unauthorized(format!("Invalid {TIMESTAMP_HEADER}")))?;
+    if signed_at.abs_diff(now) > MAX_SIGNATURE_AGE_SECS {
+        return Err(AuthError::unauthorized("Signature expired"));
+    }
+    let signature = headers.get(&SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| hex::decode(value).ok())
+        .ok_or_else(|| AuthError::unauthorized(format!("Invalid {SIGNATURE_HEADER}")))?;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(signed_prefix(timestamp, method, uri).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| AuthError::unauthorized("Invalid signature"))
+}
+
+/// What a signature covers before the body
+fn signed_prefix(timestamp: &str, method: &Method, uri: &Uri) -> String {
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    format!("{timestamp}.{method}.{path}.")
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth {
+            token: Some("pre-shared".to_string()),
+            hmac_secret: Some(b"backend".to_vec()),
+            jwt: Some((
+                jsonwebtoken::DecodingKey::from_secret(b"platform"),
+                jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
+            )),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs.iter()
+            .map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn solve() -> Uri {
+        Uri::from_static("/v2/solve")
+    }
+
+    fn sign(secret: &[u8], timestamp: u64, request: &str, body: &[u8]) -> String {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).unwrap();
+        mac.update(format!("{timestamp}.{request}.").as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn bearer_token_and_jwt() {
+        let auth = auth();
+        assert_eq!(auth.authenticate(&Method::POST, &solve(), &headers(&[("authorization", "Bearer pre-shared")]), b"").unwrap(), Principal::default());
+        assert_eq!(auth.authenticate(&Method::POST, &solve(), &headers(&[]), b"").unwrap_err().code, ErrorCode::Unauthorized);
+        assert_eq!(auth.authenticate(&Method::POST, &solve(), &headers(&[("authorization", "Bearer guess")]), b"").unwrap_err().code, ErrorCode::Unauthorized);
+
+        let claims = serde_json::json!({ "sessionId": "s1", "course": "intro-to-solana", "exp": now_secs() + 60 });
+        let jwt = jsonwebtoken::encode(&Default::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(b"platform")).unwrap();
+        let principal = auth.authenticate(&Method::POST, &solve(), &headers(&[("authorization", &format!("Bearer {jwt}"))]), b"").unwrap();
+        assert_eq!(principal.session_id.as_deref(), Some("s1"));
+        assert!(principal.authorize_course("intro-to-solana").is_ok());
+        assert!(principal.authorize_internal().is_err());
+        assert_eq!(principal.authorize_course("intro-to-near").unwrap_err().code, ErrorCode::Forbidden);
+
+        let expired = serde_json::json!({ "sessionId": "s1", "course": "intro-to-solana", "exp": now_secs() - 3600 });
+        let jwt = jsonwebtoken::encode(&Default::default(), &expired, &jsonwebtoken::EncodingKey::from_secret(b"platform")).unwrap();
+        assert!(auth.authenticate(&Method::POST, &solve(), &headers(&[("authorization", &format!("Bearer {jwt}"))]), b"").is_err());
+    }
+
+    #[test]
+    fn jwt_without_restrictions() {
+        let auth = auth();
+        // a student token that does not say what it is for would allow everything
+        for claims in [
+            serde_json::json!({ "exp": now_secs() + 60 }),
+            serde_json::json!({ "sessionId": "s1", "exp": now_secs() + 60 }),
+            serde_json::json!({ "course": "intro-to-solana", "exp": now_secs() + 60 }),
+        ] {
+            let jwt = jsonwebtoken::encode(&Default::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(b"platform")).unwrap();
+            let err = auth.authenticate(&Method::POST, &solve(), &headers(&[("authorization", &format!("Bearer {jwt}"))]), b"").unwrap_err();
+            assert_eq!(err.code, ErrorCode::Unauthorized, "{claims}");
+        }
+    }
+
+    #[test]
+    fn signed_requests() {
+        let body = br#"{"runner":"solana"}"#;
+        let now = now_secs();
+        let signed = |secret: &[u8], timestamp: u64, request: &str, body: &[u8]| {
+            let headers = headers(&[("x-agora-timestamp", &timestamp.to_string()), ("x-agora-signature", &sign(secret, timestamp, request, body))]);
+            verify_signature(b"backend", &Method::POST, &solve(), &headers, br#"{"runner":"solana"}"#, now)
+        };
+        assert!(signed(b"backend", now, "POST./v2/solve", body).is_ok());
+        assert!(signed(b"backend", now, "POST./v2/solve", b"{}").is_err());
+        assert!(signed(b"other", now, "POST./v2/solve", body).is_err());
+        assert!(signed(b"backend", now - 3600, "POST./v2/solve", body).is_err());
+        // a signature is only good for the request it was made for
+        assert!(signed(b"backend", now, "POST./v2/sandbox", body).is_err());
+        assert!(signed(b"backend", now, "PUT./v2/solve", body).is_err());
+
+        let headers = headers(&[("x-agora-timestamp", &now.to_string()), ("x-agora-signature", &sign(b"backend", now, "GET./v1/sessions/s1/attempts?limit=5", b""))]);
+        let history = Uri::from_static("/v1/sessions/s1/attempts?limit=5");
+        assert!(verify_signature(b"backend", &Method::GET, &history, &headers, b"", now).is_ok());
+        let other_session = Uri::from_static("/v1/sessions/s2/attempts?limit=5");
+        assert!(verify_signature(b"backend", &Method::GET, &other_session, &headers, b"", now).is_err());
+        assert!(verify_signature(b"backend", &Method::DELETE, &Uri::from_static("/internal/workers/w1"), &headers, b"", now).is_err());
+    }
+}
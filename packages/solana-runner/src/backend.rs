@@ -0,0 +1,448 @@
+//! The Solana tools, as seen by the grading pipeline (see `executor.rs`)
+//!
+//! `Cargo` runs the installed tools. `Replay` plays back their recorded output instead, so that the pipeline, and
+//! the runner as a whole, can be tested without a Solana toolchain; a toolchain profile uses it when it names a
+//! recording (see `ToolchainProfile::replay`).
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::process::Command;
+use tracing::Instrument;
+
+use crate::lesson::{LessonKind, TestMode};
+use crate::metrics;
+use crate::toolchain::ToolchainProfile;
+use crate::validator::Validator;
+
+/// A step of grading that needs the Solana tools; each runs in the project directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Fill the empty `target/` with the build output of the warm project of the lesson kind, so that the dependencies
+    /// are not compiled again
+    WarmUp(LessonKind),
+    /// Compile a plain `solana_program` crate with `cargo build-sbf`, with the given features of the crate
+    BuildNative { features: Vec<String> },
+    /// Compile an Anchor program, without its IDL
+    BuildAnchor { features: Vec<String> },
+    /// Generate the IDL of an Anchor program into `target/idl/lesson.json`
+    GenerateIdl,
+    /// Run the `lesson_tests`
+    Test { mode: TestMode, features: Vec<String> },
+    /// Build the `lesson_tests` without running them; cargo reports the executable in its JSON messages on stdout
+    BuildTests { features: Vec<String> },
+    /// Run a test executable built by `BuildTests` against the program in `target/deploy`
+    RunTests(PathBuf),
+    /// Run a test executable built by `BuildTests` against a validator with the program deployed
+    RunClientTests { executable: PathBuf, rpc_url: String, program_id: String, program_so: PathBuf },
+    /// Run the scenarios of a job file (see `scenario.rs`) against the program in `target/deploy`; the outcomes are
+    /// printed as JSON
+    RunScenarios(PathBuf),
+}
+
+impl Step {
+    /// Name of the step in recordings
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::WarmUp(_) => "warmUp",
+            Step::BuildNative { .. } => "buildNative",
+            Step::BuildAnchor { .. } => "buildAnchor",
+            Step::GenerateIdl => "generateIdl",
+            Step::Test { .. } => "test",
+            Step::BuildTests { .. } => "buildTests",
+            Step::RunTests(_) => "runTests",
+            Step::RunClientTests { .. } => "runClientTests",
+            Step::RunScenarios(_) => "runScenarios",
+        }
+    }
+
+    const NAMES: [&'static str; 9] = [
+        "warmUp", "buildNative", "buildAnchor", "generateIdl", "test", "buildTests", "runTests", "runClientTests", "runScenarios",
+    ];
+}
+
+/// Arguments of `cargo build-sbf` and `cargo test-sbf` the runner uses when the tools support them; older tools lack
+/// some, e.g. `--arch`
+pub const SBF_ARGS: [&str; 3] = ["--sbf-out-dir", "--arch", "--features"];
+
+/// What the SBF tools of a toolchain are, probed once per profile (see `ToolchainProfile::capabilities`)
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Versions by tool, e.g. `platform-tools` → `v1.41`
+    pub versions: BTreeMap<String, String>,
+    /// Those of `SBF_ARGS` that both `cargo build-sbf` and `cargo test-sbf` accept
+    pub args: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new(version: &str, args: Vec<String>) -> Self {
+        // e.g. `solana-cargo-build-sbf 1.18.26, platform-tools v1.41, rustc 1.75.0`
+        let versions = version.split(", ")
+            .filter_map(|tool| tool.trim().rsplit_once(' '))
+            .map(|(tool, version)| (tool.to_string(), version.to_string()))
+            .collect();
+        Self { versions, args }
+    }
+
+    pub fn supports(&self, arg: &str) -> bool {
+        self.args.iter().any(|supported| supported == arg)
+    }
+
+    /// Fail when an argument a lesson or profile asks for is not supported
+    pub fn require(&self, arg: &str) -> anyhow::Result<()> {
+        if !self.supports(arg) {
+            anyhow::bail!("The installed cargo build-sbf does not support {arg}");
+        }
+        Ok(())
+    }
+}
+
+/// What a step printed, and how it exited
+#[derive(Debug)]
+pub struct StepOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<String>,
+    pub stderr: Vec<String>,
+}
+
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    /// Versions of the tools, identifying the results they produce
+    async fn version(&self) -> anyhow::Result<String>;
+
+    /// Probe which SBF subcommands and arguments the tools have
+    async fn capabilities(&self) -> anyhow::Result<Capabilities>;
+
+    /// Run a step in the project directory `dir`; a failing tool is not an error, but an unsuccessful `StepOutput::status`
+    async fn run(&self, step: Step, dir: &Path) -> anyhow::Result<StepOutput>;
+
+    /// Start a local validator with the compiled program deployed, for the `RunClientTests` step
+    async fn start_validator(&self, dir: &Path, program_id: &str, program_so: &Path) -> anyhow::Result<Validator>;
+}
+
+/// The tools installed for a toolchain profile
+pub struct Cargo {
+    toolchain: ToolchainProfile,
+}
+
+impl Cargo {
+    pub fn new(toolchain: ToolchainProfile) -> Self {
+        Self { toolchain }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Cargo {
+    async fn version(&self) -> anyhow::Result<String> {
+        let output = self.toolchain.cargo_cmd().args(["build-sbf", "--version"]).output().await?;
+        if !output.status.success() {
+            anyhow::bail!("cargo build-sbf --version failed: {}", output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().replace('\n', ", "))
+    }
+
+    async fn capabilities(&self) -> anyhow::Result<Capabilities> {
+        let version = self.version().await?;
+        let mut args = SBF_ARGS.map(str::to_string).to_vec();
+        for subcommand in ["build-sbf", "test-sbf"] {
+            let output = self.toolchain.cargo_cmd().args([subcommand, "--help"]).output().await?;
+            if !output.status.success() {
+                anyhow::bail!("cargo {subcommand} is not installed: {}", output.status);
+            }
+            let help = String::from_utf8_lossy(&output.stdout);
+            args.retain(|arg| help.contains(arg.as_str()));
+        }
+        Ok(Capabilities::new(&version, args))
+    }
+
+    async fn run(&self, step: Step, dir: &Path) -> anyhow::Result<StepOutput> {
+        let mut cmd = match step {
+            Step::WarmUp(kind) => {
+                // this is a hack to avoid recompiling dependencies, because rsync is very careful about preserving timestamps
+                let mut cmd = Command::new("rsync");
+                cmd.arg("-azi").arg("--delete").arg(self.toolchain.warm_target(kind)).arg(dir.join("target/"));
+                cmd
+            }
+            Step::BuildNative { features } => {
+                let mut cmd = self.toolchain.cargo_cmd();
+                cmd.args(["build-sbf", "--offline"]).args(self.sbf_args(dir, &features).await?);
+                cmd
+            }
+            Step::BuildAnchor { features } => {
+                // the arguments after `--` are passed on to `cargo build-sbf`; Anchor chooses the output directory
+                let mut cmd = self.toolchain.command("anchor");
+                cmd.args(["build", "--no-idl", "--", "--offline"]).args(features_args(&features));
+                cmd
+            }
+            Step::GenerateIdl => {
+                let mut cmd = self.toolchain.command("anchor");
+                cmd.args(["idl", "build", "--out"]).arg(dir.join("target/idl/lesson.json")).args(["--", "--offline"]);
+                cmd
+            }
+            Step::Test { mode, features } => {
+                let mut cmd = self.toolchain.cargo_cmd();
+                match mode {
+                    TestMode::Native => {
+                        // without the out dir variables, `solana-program-test` does not look for the `.so` at all
+                        cmd.args(["test", "--jobs", "1", "--test", "lesson_tests"]).args(features_args(&features));
+                        cmd.env_remove("BPF_OUT_DIR").env_remove("SBF_OUT_DIR");
+                    }
+                    TestMode::Sbf | TestMode::Prebuilt | TestMode::Validator => {
+                        let deploy_dir = dir.join("target/deploy");
+                        cmd.args(["test-sbf", "--jobs", "1", "--test", "lesson_tests"]).args(self.sbf_args(dir, &features).await?);
+                        cmd.env("BPF_OUT_DIR", &deploy_dir).env("SBF_OUT_DIR", &deploy_dir);
+                    }
+                    TestMode::Auto => {
+                        cmd.args(["test-sbf", "--jobs", "1", "--test", "lesson_tests"]).args(self.sbf_args(dir, &features).await?);
+                    }
+                }
+                cmd.arg("--offline");
+                cmd
+            }
+            Step::BuildTests { features } => {
+                // diagnostics are rendered to stderr as usual, only the artifacts are reported as JSON
+                let mut cmd = self.toolchain.cargo_cmd();
+                cmd.args(["test", "--no-run", "--jobs", "1", "--test", "lesson_tests", "--message-format", "json-render-diagnostics", "--offline"]);
+                cmd.args(features_args(&features));
+                cmd
+            }
+            Step::RunTests(executable) => {
+                let deploy_dir = dir.join("target/deploy");
+                let mut cmd = Command::new(executable);
+                cmd.args(["--test-threads", "1"]);
+                cmd.env("BPF_OUT_DIR", &deploy_dir).env("SBF_OUT_DIR", &deploy_dir);
+                cmd
+            }
+            Step::RunClientTests { executable, rpc_url, program_id, program_so } => {
+                let mut cmd = Command::new(executable);
+                cmd.args(["--test-threads", "1"]);
+                cmd.env("AGORA_RPC_URL", rpc_url).env("AGORA_PROGRAM_ID", program_id).env("AGORA_PROGRAM_SO", program_so);
+                cmd
+            }
+            Step::RunScenarios(job) => {
+                let deploy_dir = dir.join("target/deploy");
+                let mut cmd = self.toolchain.command(&self.toolchain.scenario_host.to_string_lossy());
+                cmd.arg(job);
+                cmd.env("BPF_OUT_DIR", &deploy_dir).env("SBF_OUT_DIR", &deploy_dir);
+                cmd
+            }
+        };
+        cmd.current_dir(dir);
+        tracing_execute(&mut cmd).await
+    }
+
+    async fn start_validator(&self, dir: &Path, program_id: &str, program_so: &Path) -> anyhow::Result<Validator> {
+        Validator::start(self.toolchain.command("solana-test-validator"), dir, program_id, program_so).await
+    }
+}
+
+impl Cargo {
+    /// Arguments shared by `cargo build-sbf` and `cargo test-sbf`, so that the program is built the same way by both
+    async fn sbf_args(&self, dir: &Path, features: &[String]) -> anyhow::Result<Vec<OsString>> {
+        let capabilities = self.toolchain.capabilities().await?;
+        let mut args = Vec::new();
+        // the default, but explicitly, since the tests look for the program there
+        if capabilities.supports("--sbf-out-dir") {
+            args.extend([OsString::from("--sbf-out-dir"), dir.join("target/deploy").into()]);
+        }
+        if let Some(arch) = &self.toolchain.arch {
+            capabilities.require("--arch")?;
+            args.extend([OsString::from("--arch"), arch.into()]);
+        }
+        if !features.is_empty() {
+            capabilities.require("--features")?;
+            args.extend(features_args(features));
+        }
+        Ok(args)
+    }
+}
+
+fn features_args(features: &[String]) -> Vec<OsString> {
+    if features.is_empty() {
+        return vec![];
+    }
+    vec!["--features".into(), features.join(",").into()]
+}
+
+async fn tracing_execute(cmd: &mut Command) -> anyhow::Result<StepOutput> {
+    cmd.kill_on_drop(true);
+    tracing::debug!("Executing: {:?}", cmd);
+    let mut child = cmd
+        .stderr(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let stderr = tokio::spawn(read_lines(child.stderr.take().unwrap(), "stderr").in_current_span());
+    let stdout = tokio::spawn(read_lines(child.stdout.take().unwrap(), "stdout").in_current_span());
+    let mut kill_guard = KillGuard { finished: false };
+    let status = child.wait().await?;
+    kill_guard.finished = true;
+    if was_killed(&status) {
+        metrics::SUBPROCESS_KILLS.inc();
+    }
+    Ok(StepOutput { status, stdout: stdout.await?, stderr: stderr.await? })
+}
+
+async fn read_lines(output: impl tokio::io::AsyncRead + Unpin, name: &'static str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut reader = tokio::io::BufReader::new(output).lines();
+    while let Ok(Some(line)) = reader.next_line().await {
+        tracing::debug!("{name}: {line}");
+        lines.push(line);
+    }
+    lines
+}
+
+/// Counts the child as killed when `tracing_execute` is dropped while waiting for it, because of `kill_on_drop`
+struct KillGuard {
+    finished: bool,
+}
+
+impl Drop for KillGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            metrics::SUBPROCESS_KILLS.inc();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn was_killed(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().is_some()
+}
+
+#[cfg(not(unix))]
+fn was_killed(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Status of a process that exited with the code
+#[cfg(unix)]
+fn recorded_status(exit_code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    // the wait status carries the code in its second byte
+    ExitStatus::from_raw((exit_code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn recorded_status(exit_code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(exit_code as u32)
+}
+
+/// Recorded output of the tools, played back for every submission regardless of its files
+///
+/// A recording is a JSON file like `tests/recordings/passing.json`: the `version` of the tools, and the output of
+/// each step by its `Step::name`. Steps without a recording succeed without printing anything. A validator is
+/// never started; when `startValidator` is recorded with an exit code, starting it fails with its `stderr`.
+/// The tools support all of `SBF_ARGS`, unless the recording lists the supported ones as `sbfArgs`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replay {
+    version: String,
+    #[serde(default)]
+    sbf_args: Option<Vec<String>>,
+    #[serde(default)]
+    steps: HashMap<String, Recording>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Recording {
+    #[serde(default)]
+    exit_code: i32,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    /// Files the step created, by their path in the project directory, e.g. the compiled program
+    #[serde(default)]
+    files: BTreeMap<PathBuf, String>,
+    /// How long the step takes, e.g. to test timeouts
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+impl Replay {
+    const START_VALIDATOR: &'static str = "startValidator";
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read recording {}", path.display()))?;
+        let replay: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid recording in {}", path.display()))?;
+        let known = |name: &str| Step::NAMES.contains(&name) || name == Self::START_VALIDATOR;
+        if let Some(unknown) = replay.steps.keys().find(|name| !known(name)) {
+            anyhow::bail!("Unknown step {unknown:?} in {}; the steps are: {}, {}", path.display(), Step::NAMES.join(", "), Self::START_VALIDATOR);
+        }
+        Ok(replay)
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Replay {
+    async fn version(&self) -> anyhow::Result<String> {
+        Ok(self.version.clone())
+    }
+
+    async fn capabilities(&self) -> anyhow::Result<Capabilities> {
+        let args = self.sbf_args.clone().unwrap_or_else(|| SBF_ARGS.map(str::to_string).to_vec());
+        Ok(Capabilities::new(&self.version, args))
+    }
+
+    async fn run(&self, step: Step, dir: &Path) -> anyhow::Result<StepOutput> {
+        tracing::debug!("Replaying {}", step.name());
+        let unrecorded = Recording::default();
+        let recording = self.steps.get(step.name()).unwrap_or(&unrecorded);
+        tokio::time::sleep(std::time::Duration::from_millis(recording.delay_ms)).await;
+        for (path, content) in &recording.files {
+            let path = dir.join(path);
+            path.parent().map(std::fs::create_dir_all);
+            std::fs::write(&path, content)?;
+        }
+        let lines = |output: &str| output.lines().map(str::to_string).collect();
+        Ok(StepOutput {
+            status: recorded_status(recording.exit_code),
+            stdout: lines(&recording.stdout),
+            stderr: lines(&recording.stderr),
+        })
+    }
+
+    async fn start_validator(&self, _dir: &Path, program_id: &str, _program_so: &Path) -> anyhow::Result<Validator> {
+        tracing::debug!("Replaying {} of {program_id}", Self::START_VALIDATOR);
+        if let Some(recording) = self.steps.get(Self::START_VALIDATOR).filter(|recording| recording.exit_code != 0) {
+            anyhow::bail!("The validator exited on startup (exit status: {}): {}", recording.exit_code, recording.stderr.trim());
+        }
+        Ok(Validator::replayed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sbf_args_by_capabilities() {
+        let profile = ToolchainProfile {
+            name: "replay-sbf-args".to_string(),
+            replay: Some(PathBuf::from("tests/recordings/passed.json")),
+            arch: Some("sbfv2".to_string()),
+            ..Default::default()
+        };
+        let capabilities = profile.capabilities().await.unwrap();
+        assert_eq!(capabilities.versions["platform-tools"], "v1.41");
+        assert_eq!(capabilities.versions["solana-cargo-build-sbf"], "1.18.26");
+
+        let args = Cargo::new(profile).sbf_args(Path::new("/tmp/session"), &["no-entrypoint".to_string()]).await.unwrap();
+        assert_eq!(args, ["--sbf-out-dir", "/tmp/session/target/deploy", "--arch", "sbfv2", "--features", "no-entrypoint"]);
+
+        let old_tools = Capabilities::new("solana-cargo-build-sbf 1.14.29", vec!["--sbf-out-dir".to_string()]);
+        assert!(old_tools.require("--arch").is_err());
+        assert!(old_tools.require("--sbf-out-dir").is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+//! Cache of grading results, so that re-running an unchanged submission does not build it again
+//!
+//! The key covers everything the result depends on: the lesson and the version of its template, the submitted files,
+//! and the toolchain. Editing the lesson tests changes the key, so stale results are never served.
+
+use std::num::NonZeroUsize;
+
+use sha2::Digest;
+
+use crate::types::{ErrorCode, TEditorFile, TTestResponse};
+
+pub struct ResultCache {
+    entries: Option<std::sync::Mutex<lru::LruCache<String, TTestResponse>>>,
+}
+
+impl ResultCache {
+    /// A cache holding at most `capacity` results; zero disables caching
+    pub fn new(capacity: usize) -> Self {
+        let entries = NonZeroUsize::new(capacity)
+            .map(|capacity| std::sync::Mutex::new(lru::LruCache::new(capacity)));
+        Self { entries }
+    }
+
+    /// Capacity from `AGORA_RESULT_CACHE_SIZE`
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("AGORA_RESULT_CACHE_SIZE").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(256);
+        Self::new(capacity)
+    }
+
+    pub fn get(&self, key: &str) -> Option<TTestResponse> {
+        let entries = self.entries.as_ref()?;
+        let response = entries.lock().unwrap().get(key).cloned();
+        crate::metrics::cache_lookup("result", response.is_some());
+        response.map(|response| response.with_cached())
+    }
+
+    /// Remember the response, if it is a property of the submission rather than of the runner's state
+    pub fn put(&self, key: String, response: &TTestResponse) {
+        let Some(entries) = self.entries.as_ref() else {
+            return;
+        };
+        let deterministic = match response.error_code() {
+            None => true,
+            Some(code) => matches!(code, ErrorCode::TestFailure | ErrorCode::CompileError | ErrorCode::TestCompileError),
+        };
+        if deterministic {
+            entries.lock().unwrap().put(key, response.clone());
+        }
+    }
+}
+
+/// Key of the submission; files are normalized, so that their order and line endings do not matter
+pub fn cache_key(lesson_slug: &str, lesson_fingerprint: &str, files: &[TEditorFile], toolchain_version: &str) -> String {
+    let mut files = files.iter()
+        .map(|file| (file.path.as_str(), file.content.replace("\r\n", "\n")))
+        .collect::<Vec<_>>();
+    files.sort();
+    let mut hasher = sha2::Sha256::new();
+    for part in [lesson_slug, lesson_fingerprint, toolchain_version] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    for (path, content) in files {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(content.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TTest;
+
+    fn file(path: &str, content: &str) -> TEditorFile {
+        TEditorFile { path: path.to_string(), content: content.to_string() }
+    }
+
+    #[test]
+    fn key_ignores_file_order_and_line_endings() {
+        let a = cache_key("sysvar", "f1", &[file("lib.rs", "fn a() {}\r\n"), file("b.rs", "")], "1.18");
+        let b = cache_key("sysvar", "f1", &[file("b.rs", ""), file("lib.rs", "fn a() {}\n")], "1.18");
+        assert_eq!(a, b);
+        assert_ne!(a, cache_key("sysvar", "f2", &[file("lib.rs", "fn a() {}\n"), file("b.rs", "")], "1.18"));
+        assert_ne!(a, cache_key("sysvar", "f1", &[file("lib.rs", "fn a() {}\n"), file("b.rs", "")], "2.1"));
+    }
+
+    #[test]
+    fn caches_deterministic_results_only() {
+        let cache = ResultCache::new(1);
+        cache.put("busy".to_string(), &TTestResponse::error(ErrorCode::RunnerBusy, "Runner is busy"));
+        assert!(cache.get("busy").is_none());
+
+        cache.put("a".to_string(), &TTestResponse::from(vec![TTest::ok("test_sysvar")]));
+        let cached = serde_json::to_string(&cache.get("a").unwrap()).unwrap();
+        assert_eq!(r##"{"passed":true,"tests":[{"title":"test_sysvar","passed":true}],"cached":true}"##, cached);
+
+        // capacity is one, so the least recently used entry goes
+        cache.put("b".to_string(), &TTestResponse::error(ErrorCode::CompileError, "Failed to compile code"));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+    }
+}
@@ -0,0 +1,109 @@
+//! Command line; without a command, the runner serves HTTP as configured by the `AGORA_*` variables
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::types::{TEditorFile, TTestRequest};
+
+#[derive(Debug, clap::Parser)]
+#[command(version, about = "Grades submissions of Solana lessons")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Grade a submission without starting the server, e.g. while writing a lesson
+    ///
+    /// The lessons are read from `lessons-code` in the current directory, like when serving.
+    /// Exits with 0 when all tests passed, 1 otherwise.
+    Solve(SolveArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SolveArgs {
+    #[arg(long, default_value = "intro-to-solana")]
+    pub course: String,
+    /// Lesson slug, i.e. the name of the lesson directory without the `solana-` prefix
+    #[arg(long, required_unless_present = "request")]
+    pub lesson: Option<String>,
+    /// Toolchain profile to grade with, by default the one of the lesson; see `AGORA_TOOLCHAINS`
+    #[arg(long)]
+    pub toolchain: Option<String>,
+    /// Grade a saved solve request, e.g. `tests/solve01.json`, instead of files
+    #[arg(long, conflicts_with_all = ["lesson", "files"])]
+    pub request: Option<PathBuf>,
+    /// Print the response as JSON, like the server would
+    #[arg(long)]
+    pub json: bool,
+    /// Submitted source files, put into `src/` by their name; the files of a directory keep their path within it
+    #[arg(required_unless_present = "request")]
+    pub files: Vec<PathBuf>,
+}
+
+/// Grade the submission and print the response; returns whether it passed
+pub async fn solve(args: SolveArgs) -> anyhow::Result<bool> {
+    let mut test_request = match &args.request {
+        Some(path) => {
+            let request = std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+            serde_json::from_str::<TTestRequest>(&request)
+                .with_context(|| format!("Invalid solve request in {}", path.display()))?
+        }
+        None => TTestRequest {
+            runner: crate::toolchain::RUNNER_NAME.to_string(),
+            r#type: None,
+            course_slug: args.course.clone(),
+            lesson_slug: args.lesson.clone().unwrap_or_default(),
+            files: submitted_files(&args.files)?,
+            image: None,
+        },
+    };
+    if let Some(toolchain) = &args.toolchain {
+        test_request.runner = toolchain.clone();
+        test_request.image = None;
+    }
+    if crate::COURSE.lesson(&test_request.lesson_slug).is_none() {
+        let mut slugs = crate::COURSE.lessons_by_slug.keys().map(String::as_str).collect::<Vec<_>>();
+        slugs.sort();
+        anyhow::bail!("Unknown lesson {:?}; the lessons are: {}", test_request.lesson_slug, slugs.join(", "));
+    }
+
+    let (response, _) = crate::solve_raw(test_request).await;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else {
+        println!("{response}");
+    }
+    Ok(response.error_code().is_none())
+}
+
+fn submitted_files(paths: &[PathBuf]) -> anyhow::Result<Vec<TEditorFile>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut sources = Vec::new();
+            crate::lesson::collect_files(path, &mut sources)?;
+            sources.sort();
+            for source in sources {
+                let relative = source.strip_prefix(path)?.to_string_lossy().to_string();
+                files.push(editor_file(&source, relative)?);
+            }
+        } else {
+            let name = path.file_name()
+                .ok_or_else(|| anyhow::anyhow!("Not a file: {}", path.display()))?
+                .to_string_lossy()
+                .to_string();
+            files.push(editor_file(path, name)?);
+        }
+    }
+    Ok(files)
+}
+
+fn editor_file(source: &Path, path: String) -> anyhow::Result<TEditorFile> {
+    let content = std::fs::read_to_string(source)
+        .with_context(|| format!("Cannot read {}", source.display()))?;
+    Ok(TEditorFile { path, content })
+}
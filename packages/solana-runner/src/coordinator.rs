@@ -0,0 +1,454 @@
+//! Coordinator of worker processes, when grading is scaled separately from the API (`AGORA_MODE=coordinator`)
+//!
+//! The coordinator accepts the solve requests and keeps them in a queue. Workers (see `worker.rs`) register with the
+//! toolchain profiles they have installed, and long-poll for jobs over HTTP; so the coordinator never connects to
+//! them, and any number of workers can run on one machine. A worker that stops sending heartbeats is forgotten, and
+//! the jobs it was grading go back to the front of the queue; when no worker is left for their toolchain, the queued
+//! jobs fail instead of waiting for one.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{ErrorCode, TTestRequest, TTestResponse};
+
+/// How often workers send heartbeats
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// A worker not heard of for this long is considered dead
+const WORKER_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a worker's request for the next job is held open, when there is none
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// A job is given to at most this many workers, in case it is what makes them die
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Registration {
+    /// Names of the toolchain profiles the worker has installed
+    pub toolchains: Vec<String>,
+    /// How many jobs the worker grades at once
+    pub slots: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Registered {
+    pub worker_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobAssignment {
+    pub job_id: u64,
+    pub request: TTestRequest,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResult {
+    pub response: TTestResponse,
+    /// Duration of the grading phases in milliseconds, by phase name
+    #[serde(default)]
+    pub timings: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerStatus {
+    pub worker_id: String,
+    pub toolchains: Vec<String>,
+    pub slots: u32,
+    pub running_jobs: usize,
+    pub last_seen_ms_ago: u128,
+}
+
+/// The worker is not registered, or was forgotten; it has to register again
+#[derive(Debug)]
+pub struct UnknownWorker;
+
+pub struct Coordinator {
+    state: std::sync::Mutex<State>,
+    /// Wakes up the workers waiting for a job
+    job_queued: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct State {
+    workers: HashMap<String, Worker>,
+    jobs: HashMap<u64, Job>,
+    queue: VecDeque<u64>,
+    next_job_id: u64,
+}
+
+struct Worker {
+    toolchains: Vec<String>,
+    slots: u32,
+    last_seen: Instant,
+}
+
+struct Job {
+    toolchain: String,
+    /// Serialized, because the request is sent again when the job is requeued
+    request: serde_json::Value,
+    /// The worker grading the job, if it was handed out
+    worker_id: Option<String>,
+    attempts: u32,
+    done: Option<tokio::sync::oneshot::Sender<JobResult>>,
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self { state: Default::default(), job_queued: tokio::sync::Notify::new() }
+    }
+}
+
+impl Coordinator {
+    pub fn register(&self, registration: Registration) -> Registered {
+        let worker_id = uuid::Uuid::new_v4().to_string();
+        tracing::info!("Worker {worker_id} registered with toolchains {:?} and {} slot(s)", registration.toolchains, registration.slots);
+        let worker = Worker { toolchains: registration.toolchains, slots: registration.slots, last_seen: Instant::now() };
+        self.state.lock().unwrap().workers.insert(worker_id.clone(), worker);
+        // queued jobs may be waiting for just this toolchain
+        self.job_queued.notify_waiters();
+        Registered { worker_id }
+    }
+
+    pub fn heartbeat(&self, worker_id: &str) -> Result<(), UnknownWorker> {
+        let mut state = self.state.lock().unwrap();
+        let worker = state.workers.get_mut(worker_id).ok_or(UnknownWorker)?;
+        worker.last_seen = Instant::now();
+        Ok(())
+    }
+
+    /// Forget a worker that is shutting down; its unfinished jobs are requeued
+    pub fn deregister(&self, worker_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.workers.remove(worker_id).is_some() {
+            tracing::info!("Worker {worker_id} left");
+            state.requeue_jobs_of(worker_id);
+            state.fail_unserved_jobs();
+            drop(state);
+            self.job_queued.notify_waiters();
+        }
+    }
+
+    /// Queue the request and wait until a worker grades it
+    ///
+    /// Gives up with `RunnerBusy` when too many jobs are queued, or when there is no worker for the toolchain.
+    /// Dropping the returned future, e.g. when the client disconnects or the caller times out, forgets the job.
+    pub async fn dispatch(&self, request: &TTestRequest, toolchain: &str, max_queue: usize) -> JobResult {
+        let (done, result) = tokio::sync::oneshot::channel();
+        let job_id = {
+            let mut state = self.state.lock().unwrap();
+            if !state.workers.values().any(|worker| worker.toolchains.iter().any(|name| name == toolchain)) {
+                tracing::warn!("No worker for toolchain {toolchain}");
+                return error_result(ErrorCode::RunnerBusy, "No worker available, try again later");
+            }
+            if state.queue.len() >= max_queue {
+                tracing::warn!("Queue is full");
+                return error_result(ErrorCode::RunnerBusy, "Runner is busy, try again later");
+            }
+            state.next_job_id += 1;
+            let job_id = state.next_job_id;
+            let job = Job {
+                toolchain: toolchain.to_string(),
+                request: serde_json::to_value(request).expect("requests are serializable"),
+                worker_id: None,
+                attempts: 0,
+                done: Some(done),
+            };
+            state.jobs.insert(job_id, job);
+            state.queue.push_back(job_id);
+            crate::metrics::QUEUE_DEPTH.set(state.queue.len() as i64);
+            tracing::info!("Queued job {job_id}");
+            job_id
+        };
+        let _waiting = Waiting { coordinator: self, job_id };
+        self.job_queued.notify_waiters();
+        result.await.unwrap_or_else(|_| error_result(ErrorCode::InternalError, "Job was lost"))
+    }
+
+    /// Hand out the oldest queued job the worker has the toolchain for, waiting up to `wait` for one
+    pub async fn next_job(&self, worker_id: &str, wait: Duration) -> Result<Option<JobAssignment>, UnknownWorker> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // registered before looking at the queue, so that a job queued meanwhile is not missed
+            let queued = self.job_queued.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+            if let Some(assignment) = self.take_job(worker_id)? {
+                return Ok(Some(assignment));
+            }
+            if tokio::time::timeout_at(deadline, queued).await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn take_job(&self, worker_id: &str) -> Result<Option<JobAssignment>, UnknownWorker> {
+        let mut state = self.state.lock().unwrap();
+        let worker = state.workers.get_mut(worker_id).ok_or(UnknownWorker)?;
+        worker.last_seen = Instant::now();
+        let toolchains = worker.toolchains.clone();
+        let Some(position) = state.queue.iter().position(|job_id| toolchains.contains(&state.jobs[job_id].toolchain)) else {
+            return Ok(None);
+        };
+        let job_id = state.queue.remove(position).unwrap();
+        crate::metrics::QUEUE_DEPTH.set(state.queue.len() as i64);
+        let job = state.jobs.get_mut(&job_id).unwrap();
+        job.worker_id = Some(worker_id.to_string());
+        job.attempts += 1;
+        tracing::info!("Job {job_id} goes to worker {worker_id} (attempt {})", job.attempts);
+        let request = serde_json::from_value(job.request.clone()).expect("queued requests are valid");
+        Ok(Some(JobAssignment { job_id, request }))
+    }
+
+    /// Accept the result of a job; a job the worker gave up because it is shutting down goes back to the queue
+    pub fn complete(&self, worker_id: &str, job_id: u64, result: JobResult) -> Result<(), UnknownWorker> {
+        let mut state = self.state.lock().unwrap();
+        if !state.workers.contains_key(worker_id) {
+            // the job was requeued when the worker was forgotten
+            return Err(UnknownWorker);
+        }
+        let Some(job) = state.jobs.get_mut(&job_id).filter(|job| job.worker_id.as_deref() == Some(worker_id)) else {
+            tracing::warn!("Worker {worker_id} completed job {job_id}, which is not assigned to it");
+            return Ok(());
+        };
+        if result.response.error_code() == Some(ErrorCode::RunnerRestarting) {
+            job.worker_id = None;
+            // not the job's fault
+            job.attempts -= 1;
+            state.queue.push_front(job_id);
+            crate::metrics::QUEUE_DEPTH.set(state.queue.len() as i64);
+            drop(state);
+            self.job_queued.notify_waiters();
+            return Ok(());
+        }
+        let mut job = state.jobs.remove(&job_id).unwrap();
+        tracing::info!("Job {job_id} completed by worker {worker_id}");
+        if let Some(done) = job.done.take() {
+            // the client may be gone already
+            let _ = done.send(result);
+        }
+        Ok(())
+    }
+
+    /// Forget the workers that stopped sending heartbeats, and requeue their jobs
+    pub fn reap(&self) {
+        let mut state = self.state.lock().unwrap();
+        let dead = state.workers.iter()
+            .filter(|(_, worker)| worker.last_seen.elapsed() > WORKER_TIMEOUT)
+            .map(|(worker_id, _)| worker_id.clone())
+            .collect::<Vec<_>>();
+        if dead.is_empty() {
+            return;
+        }
+        for worker_id in dead {
+            tracing::warn!("Worker {worker_id} stopped sending heartbeats");
+            state.workers.remove(&worker_id);
+            state.requeue_jobs_of(&worker_id);
+        }
+        state.fail_unserved_jobs();
+        drop(state);
+        self.job_queued.notify_waiters();
+    }
+
+    /// Resolves when no job is queued or being graded
+    pub async fn idle(&self) {
+        while !self.state.lock().unwrap().jobs.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub fn workers(&self) -> Vec<WorkerStatus> {
+        let state = self.state.lock().unwrap();
+        let mut workers = state.workers.iter()
+            .map(|(worker_id, worker)| WorkerStatus {
+                worker_id: worker_id.clone(),
+                toolchains: worker.toolchains.clone(),
+                slots: worker.slots,
+                running_jobs: state.jobs.values().filter(|job| job.worker_id.as_ref() == Some(worker_id)).count(),
+                last_seen_ms_ago: worker.last_seen.elapsed().as_millis(),
+            })
+            .collect::<Vec<_>>();
+        workers.sort_by(|a, b| a.worker_id.cmp(&b.worker_id));
+        workers
+    }
+}
+
+/// Forgets its job when the client stops waiting for the result; a worker grading it meanwhile finds it gone
+struct Waiting<'c> {
+    coordinator: &'c Coordinator,
+    job_id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.coordinator.state.lock().unwrap();
+        if state.jobs.remove(&self.job_id).is_some() {
+            tracing::info!("Job {} was abandoned", self.job_id);
+            state.queue.retain(|job_id| *job_id != self.job_id);
+            crate::metrics::QUEUE_DEPTH.set(state.queue.len() as i64);
+        }
+    }
+}
+
+impl State {
+    /// Fail the queued jobs no registered worker has the toolchain for, rather than keep them waiting
+    fn fail_unserved_jobs(&mut self) {
+        let unserved = self.queue.iter()
+            .copied()
+            .filter(|job_id| {
+                let toolchain = &self.jobs[job_id].toolchain;
+                !self.workers.values().any(|worker| worker.toolchains.contains(toolchain))
+            })
+            .collect::<Vec<_>>();
+        for job_id in unserved {
+            tracing::warn!("No worker left for job {job_id}");
+            self.queue.retain(|queued| *queued != job_id);
+            let mut job = self.jobs.remove(&job_id).unwrap();
+            if let Some(done) = job.done.take() {
+                let _ = done.send(error_result(ErrorCode::RunnerBusy, "No worker available, try again later"));
+            }
+        }
+        crate::metrics::QUEUE_DEPTH.set(self.queue.len() as i64);
+    }
+
+    fn requeue_jobs_of(&mut self, worker_id: &str) {
+        let mut orphaned = self.jobs.iter()
+            .filter(|(_, job)| job.worker_id.as_deref() == Some(worker_id))
+            .map(|(job_id, _)| *job_id)
+            .collect::<Vec<_>>();
+        // oldest first at the front of the queue
+        orphaned.sort_unstable_by(|a, b| b.cmp(a));
+        for job_id in orphaned {
+            let job = self.jobs.get_mut(&job_id).unwrap();
+            job.worker_id = None;
+            if job.attempts >= MAX_ATTEMPTS {
+                tracing::error!("Job {job_id} failed on {} workers, giving up", job.attempts);
+                let mut job = self.jobs.remove(&job_id).unwrap();
+                if let Some(done) = job.done.take() {
+                    let _ = done.send(error_result(ErrorCode::InternalError, "Grading failed repeatedly, the submission was not graded"));
+                }
+                continue;
+            }
+            tracing::info!("Requeuing job {job_id}");
+            self.queue.push_front(job_id);
+        }
+        crate::metrics::QUEUE_DEPTH.set(self.queue.len() as i64);
+    }
+}
+
+fn error_result(code: ErrorCode, message: &str) -> JobResult {
+    JobResult { response: TTestResponse::error(code, message), timings: Vec::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TTest;
+
+    fn request(lesson_slug: &str) -> TTestRequest {
+        let body = serde_json::json!({
+            "runner": "solana",
+            "courseSlug": "intro-to-solana",
+            "lessonSlug": lesson_slug,
+            "files": [{ "path": "lib.rs", "content": "" }],
+        });
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn register(coordinator: &Coordinator, toolchain: &str) -> String {
+        coordinator.register(Registration { toolchains: vec![toolchain.to_string()], slots: 1 }).worker_id
+    }
+
+    fn passed() -> JobResult {
+        JobResult { response: TTestResponse::from(vec![TTest::ok("test_sysvar")]), timings: vec![("build".to_string(), 1200)] }
+    }
+
+    #[tokio::test]
+    async fn jobs_go_to_workers_with_the_toolchain() {
+        let coordinator = std::sync::Arc::new(Coordinator::default());
+        let old = register(&coordinator, "solana-1.18");
+        let new = register(&coordinator, "agave-2.1");
+
+        let dispatched = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.dispatch(&request("sysvar"), "agave-2.1", 8).await }
+        });
+        assert!(coordinator.next_job(&old, Duration::from_millis(50)).await.unwrap().is_none());
+        let job = coordinator.next_job(&new, Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(job.request.lesson_slug, "sysvar");
+        coordinator.complete(&new, job.job_id, passed()).unwrap();
+        let result = dispatched.await.unwrap();
+        assert_eq!(result.response.error_code(), None);
+        assert_eq!(result.timings, vec![("build".to_string(), 1200)]);
+
+        let result = coordinator.dispatch(&request("sysvar"), "solana-2.2", 8).await;
+        assert_eq!(result.response.error_code(), Some(ErrorCode::RunnerBusy));
+    }
+
+    #[tokio::test]
+    async fn jobs_of_dead_workers_are_requeued() {
+        let coordinator = std::sync::Arc::new(Coordinator::default());
+        let dying = register(&coordinator, "default");
+        let dispatched = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.dispatch(&request("sysvar"), "default", 8).await }
+        });
+        let job = coordinator.next_job(&dying, Duration::from_secs(1)).await.unwrap().unwrap();
+        let healthy = register(&coordinator, "default");
+
+        // the worker stops sending heartbeats while grading
+        coordinator.state.lock().unwrap().workers.get_mut(&dying).unwrap().last_seen -= WORKER_TIMEOUT * 2;
+        coordinator.reap();
+        assert!(coordinator.next_job(&dying, Duration::ZERO).await.is_err());
+        assert!(coordinator.complete(&dying, job.job_id, passed()).is_err());
+
+        let requeued = coordinator.next_job(&healthy, Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(requeued.job_id, job.job_id);
+
+        // a worker shutting down gives the job back
+        let restarting = JobResult { response: TTestResponse::error(ErrorCode::RunnerRestarting, "Runner is restarting"), timings: Vec::new() };
+        coordinator.complete(&healthy, job.job_id, restarting).unwrap();
+        let requeued = coordinator.next_job(&healthy, Duration::from_secs(1)).await.unwrap().unwrap();
+        coordinator.complete(&healthy, requeued.job_id, passed()).unwrap();
+        assert!(dispatched.await.unwrap().response.error_code().is_none());
+    }
+
+    #[tokio::test]
+    async fn jobs_fail_when_the_last_worker_is_gone() {
+        let coordinator = std::sync::Arc::new(Coordinator::default());
+        let dying = register(&coordinator, "default");
+        let dispatched = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.dispatch(&request("sysvar"), "default", 8).await }
+        });
+        coordinator.next_job(&dying, Duration::from_secs(1)).await.unwrap().unwrap();
+
+        coordinator.state.lock().unwrap().workers.get_mut(&dying).unwrap().last_seen -= WORKER_TIMEOUT * 2;
+        coordinator.reap();
+        assert_eq!(dispatched.await.unwrap().response.error_code(), Some(ErrorCode::RunnerBusy));
+        assert!(coordinator.state.lock().unwrap().jobs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_jobs_are_forgotten() {
+        let coordinator = Coordinator::default();
+        let worker = register(&coordinator, "default");
+        let timed_out = tokio::time::timeout(Duration::from_millis(50), coordinator.dispatch(&request("sysvar"), "default", 8)).await;
+        assert!(timed_out.is_err());
+        assert!(coordinator.state.lock().unwrap().jobs.is_empty());
+        assert!(coordinator.next_job(&worker, Duration::ZERO).await.unwrap().is_none());
+
+        // a job the client stopped waiting for while it was graded
+        let request = request("sysvar");
+        let mut dispatched = Box::pin(coordinator.dispatch(&request, "default", 8));
+        assert!(tokio::time::timeout(Duration::from_millis(50), dispatched.as_mut()).await.is_err());
+        let job = coordinator.next_job(&worker, Duration::ZERO).await.unwrap().unwrap();
+        drop(dispatched);
+        assert!(coordinator.state.lock().unwrap().jobs.is_empty());
+        coordinator.complete(&worker, job.job_id, passed()).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use crate::types::TDiagnostic;
+
+/// Extract compiler diagnostics from the human-readable output of `cargo`/`rustc` (or tools wrapping them, like `anchor`)
+///
+/// Recognized are headers like `error[E0425]: message` or `warning: message`, optionally followed by
+/// a location line `  --> src/lib.rs:12:5`.
+pub fn from_cargo_stderr(lines: &[String]) -> Vec<TDiagnostic> {
+    let mut diagnostics: Vec<TDiagnostic> = Vec::new();
+    let mut awaiting_location = false;
+    for line in lines {
+        if let Some(captures) = HEADER_REGEX.captures(line) {
+            let severity = captures.name("severity").unwrap().as_str();
+            let message = captures.name("message").unwrap().as_str();
+            if is_summary(message) {
+                awaiting_location = false;
+                continue;
+            }
+            diagnostics.push(TDiagnostic::new(severity, message));
+            awaiting_location = true;
+        } else if let Some(captures) = LOCATION_REGEX.captures(line) {
+            if !awaiting_location {
+                continue;
+            }
+            awaiting_location = false;
+            let Some(diagnostic) = diagnostics.last_mut() else {
+                continue;
+            };
+            let file = captures.name("file").unwrap().as_str();
+            let line = captures.name("line").unwrap().as_str().parse().ok();
+            let column = captures.name("column").unwrap().as_str().parse().ok();
+            diagnostic.set_location(file, line, column);
+        }
+    }
+    diagnostics
+}
+
+/// Keep only the diagnostics located in the submitted files, under `src/`; the others point into the tests of the
+/// lesson, and are replaced by a single generic error, so that hidden tests are not disclosed
+pub fn in_submission(diagnostics: Vec<TDiagnostic>) -> Vec<TDiagnostic> {
+    let total = diagnostics.len();
+    let mut submitted = diagnostics.into_iter()
+        .filter(|diagnostic| diagnostic.file().is_some_and(|file| file.starts_with("src/")))
+        .collect::<Vec<_>>();
+    if submitted.len() < total {
+        submitted.push(TDiagnostic::new("error", "The tests of the lesson do not compile with the submitted code"));
+    }
+    submitted
+}
+
+/// Cargo closes a failed build with lines like "error: could not compile `x` (lib) due to 2 previous errors"; these carry no information
+fn is_summary(message: &str) -> bool {
+    message.starts_with("could not compile")
+        || message.starts_with("aborting due to")
+        || message.contains("generated") && message.contains("warning")
+}
+
+lazy_static::lazy_static!(
+    static ref HEADER_REGEX: regex::Regex = regex::Regex::new(r#"^(?P<severity>error|warning)(\[[A-Za-z0-9]+\])?: (?P<message>.+)$"#).unwrap();
+    static ref LOCATION_REGEX: regex::Regex = regex::Regex::new(r#"^\s*--> (?P<file>[^:]+):(?P<line>\d+):(?P<column>\d+)$"#).unwrap();
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cargo_stderr() {
+        let stderr = r##"   Compiling solana-lesson-anchor-counter v0.1.0 (/tmp/session_0001)
+error[E0425]: cannot find value `counter` in this scope
+  --> src/lib.rs:12:9
+   |
+12 |         counter.count += 1;
+   |         ^^^^^^^ not found in this scope
+
+warning: unused variable: `ctx`
+ --> src/lib.rs:8:20
+  |
+error: could not compile `solana-lesson-anchor-counter` (lib) due to 1 previous error
+Error: Building IDL failed"##;
+        let lines = stderr.lines().map(str::to_string).collect::<Vec<_>>();
+        let diagnostics = from_cargo_stderr(&lines);
+        let serialized = serde_json::to_string(&diagnostics).unwrap();
+        assert_eq!(r##"[{"severity":"error","message":"cannot find value `counter` in this scope","file":"src/lib.rs","line":12,"column":9},{"severity":"warning","message":"unused variable: `ctx`","file":"src/lib.rs","line":8,"column":20}]"##, serialized);
+    }
+
+    #[test]
+    fn diagnostics_in_submission() {
+        let stderr = r##"error[E0061]: this function takes 3 arguments but 2 arguments were supplied
+  --> tests/lesson_tests.rs:30:19
+warning: unused variable: `ctx`
+ --> src/lib.rs:8:20
+error: linking with `cc` failed: exit status: 1"##;
+        let lines = stderr.lines().map(str::to_string).collect::<Vec<_>>();
+        let serialized = serde_json::to_string(&in_submission(from_cargo_stderr(&lines))).unwrap();
+        assert_eq!(r##"[{"severity":"warning","message":"unused variable: `ctx`","file":"src/lib.rs","line":8,"column":20},{"severity":"error","message":"The tests of the lesson do not compile with the submitted code"}]"##, serialized);
+    }
+}
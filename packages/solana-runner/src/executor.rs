@@ -3,24 +3,147 @@ use std::process::ExitStatus;
 
 use anyhow::Context;
 use lazy_static::lazy_static;
-use tokio::io::AsyncBufReadExt;
-use tokio::process::Command;
 
-use crate::lesson::Lesson;
-use crate::types::{TTest, TTestRequest};
+use crate::backend::{Backend, Step, StepOutput};
+use crate::diagnostics;
+use crate::metrics;
+use crate::scenario::{Outcome, Scenarios};
+use crate::lesson::{Lesson, LessonKind, TestMode};
+use crate::types::{ErrorCode, ExecutionMode, TDiagnostic, TTest, TTestRequest};
+
+/// Outcome of a test run
+pub struct TestRun {
+    pub tests: Vec<TTest>,
+    /// How the program was actually executed
+    pub mode: ExecutionMode,
+}
+
+/// A failure with a known cause; any other error is reported as `ErrorCode::InternalError`
+#[derive(Debug)]
+pub struct SolveError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub diagnostics: Vec<TDiagnostic>,
+}
+
+impl SolveError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self { code, message: message.to_string(), diagnostics: vec![] }
+    }
+
+    pub fn with_diagnostics(mut self, diagnostics: Vec<TDiagnostic>) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+}
+
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SolveError {}
 
 pub struct TestExecutor {
     /// working directory for the tests
     dir: std::path::PathBuf,
     test_request: TTestRequest,
+    backend: Box<dyn Backend>,
+    /// how long each of the phases took, in the order they ran
+    timings: std::sync::Mutex<Vec<(&'static str, std::time::Duration)>>,
 }
 
 impl TestExecutor {
-    pub fn new(dir: std::path::PathBuf, test_request: TTestRequest) -> Self {
-        Self { dir, test_request }
+    pub fn new(dir: std::path::PathBuf, test_request: TTestRequest, backend: Box<dyn Backend>) -> Self {
+        Self { dir, test_request, backend, timings: Default::default() }
+    }
+
+    /// Durations of the phases that ran so far; available also when the test failed
+    pub fn timings(&self) -> Vec<(&'static str, std::time::Duration)> {
+        self.timings.lock().unwrap().clone()
+    }
+
+    /// Measure a phase until the returned guard is dropped
+    fn phase(&self, phase: &'static str) -> PhaseTimer<'_> {
+        PhaseTimer { executor: self, phase, start: std::time::Instant::now() }
+    }
+
+    pub async fn perform_test(&self, lesson: &Lesson) -> anyhow::Result<TestRun> {
+        self.prepare(lesson).await?;
+
+        // step 1c: build the tests from the template, before the submission replaces its program
+        let test_mode = lesson.meta.test_mode.unwrap_or_else(TestMode::from_env);
+        let prebuilt_tests = match test_mode {
+            TestMode::Prebuilt | TestMode::Validator => Some(self.prebuilt_tests(lesson).await?),
+            _ => None,
+        };
+
+        let mut res = Vec::new();
+        self.build(lesson, &mut res).await?;
+
+        // step 4: run the tests
+        let program_so = self.dir.join("target/deploy").join(format!("{}.so", lesson.program_name()?));
+        if let Some(scenarios) = &lesson.scenarios {
+            return self.run_scenarios(scenarios, lesson, &program_so).await;
+        }
+        tracing::info!("Running tests in {test_mode:?} mode");
+        if matches!(test_mode, TestMode::Sbf | TestMode::Prebuilt | TestMode::Validator) && !program_so.is_file() {
+            anyhow::bail!("Compiled program not found: {}", program_so.display());
+        }
+        // kept running until the tests are done
+        let mut validator = None;
+        let test_step = match prebuilt_tests {
+            Some(executable) if test_mode == TestMode::Validator => {
+                let program_id = lesson.meta.program_id.clone()
+                    .ok_or_else(|| anyhow::anyhow!("Lesson {} has no programId for the validator", lesson.slug))?;
+                let validator_timer = self.phase("validator");
+                let started = self.backend.start_validator(&self.dir, &program_id, &program_so).await?;
+                drop(validator_timer);
+                let rpc_url = started.rpc_url.clone();
+                validator = Some(started);
+                Step::RunClientTests { executable, rpc_url, program_id, program_so: program_so.clone() }
+            }
+            Some(executable) => Step::RunTests(executable),
+            None => Step::Test { mode: test_mode, features: lesson.meta.features.clone() },
+        };
+        let test_timer = self.phase("test");
+        let test = self.run(test_step, &mut res).await?;
+        drop(test_timer);
+        drop(validator);
+        if !test.status.success() {
+            tracing::warn!("Failed to execute tests");
+            // don't fail here, we want to collect test results; unless there are none, because the tests did not compile
+            if res.is_empty() {
+                let mut diagnostics = diagnostics::from_cargo_stderr(&test.stderr);
+                if !lesson.meta.hidden_tests.is_empty() {
+                    diagnostics = diagnostics::in_submission(diagnostics);
+                }
+                return Err(SolveError::new(ErrorCode::TestCompileError, format!("Failed to compile tests; exit code = {:?}", test.status))
+                    .with_diagnostics(diagnostics)
+                    .into());
+            }
+        }
+        let mode = match test_mode {
+            TestMode::Native => ExecutionMode::Native,
+            TestMode::Sbf | TestMode::Prebuilt | TestMode::Validator => ExecutionMode::Sbf,
+            TestMode::Auto if program_so.is_file() => ExecutionMode::Sbf,
+            TestMode::Auto => ExecutionMode::Native,
+        };
+        Ok(TestRun { tests: res, mode })
+    }
+
+    /// Build the submitted program, and run ad-hoc transactions against it in the SBF VM; nothing is graded
+    pub async fn invoke(&self, lesson: &Lesson, scenarios: &Scenarios) -> anyhow::Result<Outcome> {
+        self.prepare(lesson).await?;
+        self.build(lesson, &mut Vec::new()).await?;
+        let program_so = self.dir.join("target/deploy").join(format!("{}.so", lesson.program_name()?));
+        self.run_host(scenarios, lesson, &program_so).await?.into_iter().next()
+            .ok_or_else(|| anyhow::anyhow!("The scenario host reported no outcome"))
     }
 
-    pub async fn perform_test(&self, lesson: &Lesson) -> anyhow::Result<Vec<TTest>> {
+    /// Steps 1 and 1b: the project files of the lesson, and the build output of the warm project
+    async fn prepare(&self, lesson: &Lesson) -> anyhow::Result<()> {
         // prepare working directory
         // let _ = std::fs::remove_dir_all(&self.dir)
         //     .context("remove_dir_all");
@@ -28,8 +151,32 @@ impl TestExecutor {
             .context("create_dir_all")?;
         tracing::debug!("Executor dir re-created clean: {:?}", self.dir);
         // step 1: create project files
-        self.use_template(&lesson)?;
+        let template_timer = self.phase("template");
+        self.use_template(lesson)?;
+        drop(template_timer);
+
+        // step 1b: copy target from dummy project, to reduce the need to recompile dependencies;
+        // the working directory is shared by all lessons, so it is warmed up again when the kind of lesson changes
+        let target = self.dir.join("target/");
+        let warm_kind = target.join(".warm-kind");
+        let kind = format!("{:?}", lesson.meta.kind);
+        let warm = std::fs::read_to_string(&warm_kind).is_ok_and(|warm_kind| warm_kind == kind);
+        metrics::cache_lookup("warmup", warm);
+        if !warm {
+            let _warmup_timer = self.phase("warmup");
+            std::fs::create_dir_all(&target)?;
+            tracing::debug!("Copying target from dummy project to {:?}", target);
+            let warm_up = self.backend.run(Step::WarmUp(lesson.meta.kind), &self.dir).await?;
+            if !warm_up.status.success() {
+                anyhow::bail!("Failed to prepare working directory with dummy project; exit code = {:?}", warm_up.status);
+            }
+            std::fs::write(&warm_kind, kind)?;
+        }
+        Ok(())
+    }
 
+    /// Steps 2 and 3: the submitted files, and the program compiled from them
+    async fn build(&self, lesson: &Lesson, res: &mut Vec<TTest>) -> anyhow::Result<()> {
         // step 2: add source files coming with the request under `src`; one of them is expected to be the lib.rs
         let src = self.dir.join("src");
         for file in &self.test_request.files {
@@ -39,51 +186,107 @@ impl TestExecutor {
             std::fs::write(&path, &file.content)?;
         }
 
-        // step 2b: copy target from dummy project, to reduce the need to recompile dependencies
-        let target = self.dir.join("target/");
-        if !target.exists() {
-            std::fs::create_dir(&target)?;
-            // call `rsync -azi --delete /tmp/dummy-program/ /tmp/unknown/introduction/`
-            // this is a hack to avoid recompiling dependencies, because rsync is very careful about preserving timestamps
-            let dummy_program_target = std::path::PathBuf::from("/tmp/dummy-program/target/");
-            tracing::debug!("Copying target from dummy project: {:?} to {:?}", dummy_program_target, target);
-            let mut rsync_cmd = Command::new("rsync");
-            rsync_cmd
-                .arg("-azi")
-                .arg("--delete")
-                .arg(dummy_program_target)
-                .arg(&target);
-            let status = tracing_execute(&mut rsync_cmd, &mut Vec::new()).await?;
-            if !status.success() {
-                anyhow::bail!("Failed to prepare working directory with dummy project; exit code = {:?}", status);
-            }
+        // step 3: compile the project
+        tracing::info!("Compiling {:?} project", lesson.meta.kind);
+        let build_timer = self.phase("build");
+        metrics::BUILDS.inc();
+        let build = match lesson.meta.kind {
+            LessonKind::Native => self.build_native(lesson, res).await,
+            LessonKind::Anchor => self.build_anchor(lesson, res).await,
+        };
+        drop(build_timer);
+        if build.is_err() {
+            metrics::BUILD_FAILURES.inc();
         }
-        // step 3: compile the project using cargo build-bpf
-        tracing::info!("Compiling project");
+        build
+    }
 
-        let mut cargo_build_command = cargo_cmd();
-        cargo_build_command.current_dir(&self.dir);
-        cargo_build_command.arg("build-bpf");
-        cargo_build_command.arg("--offline");
-        let mut res = Vec::new();
-        let status = tracing_execute(&mut cargo_build_command, &mut res).await?;
-        if !status.success() {
+    /// Executable of the lesson tests, built once per version of the lesson in this working directory
+    ///
+    /// The tests are built against the program of the template, which they do not use; they load the submitted
+    /// program from `target/deploy`.
+    async fn prebuilt_tests(&self, lesson: &Lesson) -> anyhow::Result<std::path::PathBuf> {
+        let key = (self.dir.clone(), lesson.slug.clone());
+        let fingerprint = lesson.fingerprint()?;
+        let prebuilt = PREBUILT_TESTS.lock().unwrap().get(&key)
+            .filter(|(built_from, executable)| *built_from == fingerprint && executable.is_file())
+            .map(|(_, executable)| executable.clone());
+        metrics::cache_lookup("tests", prebuilt.is_some());
+        if let Some(executable) = prebuilt {
+            return Ok(executable);
+        }
+        let _prebuild_timer = self.phase("prebuild");
+        tracing::info!("Building the tests of lesson {}", lesson.slug);
+        let build = self.backend.run(Step::BuildTests { features: lesson.meta.features.clone() }, &self.dir).await?;
+        if !build.status.success() {
+            anyhow::bail!("Failed to build the tests of lesson {}; exit code = {:?}: {}", lesson.slug, build.status, build.stderr.join("\n"));
+        }
+        let executable = build.stdout.iter()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .find(|message| message["target"]["name"] == "lesson_tests")
+            .and_then(|message| message["executable"].as_str().map(|path| self.dir.join(path)))
+            .ok_or_else(|| anyhow::anyhow!("cargo did not report the executable of the tests of lesson {}", lesson.slug))?;
+        PREBUILT_TESTS.lock().unwrap().insert(key, (fingerprint, executable.clone()));
+        Ok(executable)
+    }
+
+    /// Run the declarative tests of the lesson in the SBF VM
+    async fn run_scenarios(&self, scenarios: &Scenarios, lesson: &Lesson, program_so: &std::path::Path) -> anyhow::Result<TestRun> {
+        let outcomes = self.run_host(scenarios, lesson, program_so).await?;
+        Ok(TestRun { tests: scenarios.check(&outcomes), mode: ExecutionMode::Sbf })
+    }
+
+    /// Outcomes of the scenarios, as reported by the scenario host
+    async fn run_host(&self, scenarios: &Scenarios, lesson: &Lesson, program_so: &std::path::Path) -> anyhow::Result<Vec<Outcome>> {
+        if !program_so.is_file() {
+            anyhow::bail!("Compiled program not found: {}", program_so.display());
+        }
+        tracing::info!("Running {} scenarios", scenarios.scenarios.len());
+        let job = self.dir.join("target/scenarios.json");
+        std::fs::write(&job, scenarios.job(&lesson.program_name()?)?)?;
+        let _test_timer = self.phase("test");
+        let run = self.backend.run(Step::RunScenarios(job), &self.dir).await?;
+        serde_json::from_str(&run.stdout.join("\n"))
+            .with_context(|| format!("The scenario host failed; exit code = {:?}: {}", run.status, run.stderr.last().map_or("", String::as_str)))
+    }
+
+    /// Run a step of the backend, collecting the test results it printed
+    async fn run(&self, step: Step, tests: &mut Vec<TTest>) -> anyhow::Result<StepOutput> {
+        let output = self.backend.run(step, &self.dir).await?;
+        tests.extend(test_results_from_stdout(&output.stdout));
+        Ok(output)
+    }
+
+    /// Compile the project using cargo build-sbf
+    async fn build_native(&self, lesson: &Lesson, res: &mut Vec<TTest>) -> anyhow::Result<()> {
+        let build = self.run(Step::BuildNative { features: lesson.meta.features.clone() }, res).await?;
+        if !build.status.success() {
             tracing::warn!("Failed to compile code");
-            anyhow::bail!("Failed to compile code; exit code = {:?}", status);
+            return Err(compile_error(build.status, &build.stderr).into());
         }
+        Ok(())
+    }
 
-        // step 4: run the tests
-        tracing::info!("Running tests");
-        let mut test_run_cmd = cargo_cmd();
-        test_run_cmd.current_dir(&self.dir);
-        test_run_cmd.args(&["test-sbf", "--jobs", "1", "--test", "lesson_tests"]);
-        test_run_cmd.arg("--offline");
-        let status = tracing_execute(&mut test_run_cmd, &mut res).await?;
-        if !status.success() {
-            tracing::warn!("Failed to execute tests");
-            // don't fail here, we want to collect test results
+    /// Compile the project using the Anchor toolchain, and generate its IDL
+    ///
+    /// The IDL is generated separately, so that its errors can be reported as diagnostics.
+    async fn build_anchor(&self, lesson: &Lesson, res: &mut Vec<TTest>) -> anyhow::Result<()> {
+        let build = self.run(Step::BuildAnchor { features: lesson.meta.features.clone() }, res).await?;
+        if !build.status.success() {
+            tracing::warn!("Failed to compile code");
+            return Err(compile_error(build.status, &build.stderr).into());
+        }
+
+        tracing::info!("Generating IDL");
+        std::fs::create_dir_all(self.dir.join("target/idl"))?;
+        let idl = self.run(Step::GenerateIdl, res).await?;
+        if !idl.status.success() {
+            tracing::warn!("Failed to generate IDL");
+            return Err(SolveError::new(ErrorCode::CompileError, format!("Failed to generate IDL; exit code = {:?}", idl.status))
+                .with_diagnostics(diagnostics::from_cargo_stderr(&idl.stderr))
+                .into());
         }
-        Ok(res)
+        Ok(())
     }
 
     fn use_template(&self, lesson: &Lesson) -> anyhow::Result<()> {
@@ -93,69 +296,38 @@ impl TestExecutor {
         let options = fs_extra::dir::CopyOptions::new()
             .overwrite(true)
             .content_only(true);
-        fs_extra::dir::copy(&template_src, &self.dir, &options)
+        fs_extra::dir::copy(template_src, &self.dir, &options)
             .context("copy_from_template")?;
         Ok(())
     }
 }
 
-fn cargo_cmd() -> Command {
-    //TODO configurable, or init script?
-    match dirs::home_dir() {
-        Some(home) => {
-            let cargo_exe = home.join(".cargo/bin/cargo");
-            // if executable, use it
-            if cargo_exe.is_file() {
-                Command::new(cargo_exe)
-            } else {
-                Command::new("cargo")
-            }
-        }
-        None => {
-            Command::new("cargo")
-        }
-    }
+fn compile_error(status: ExitStatus, stderr: &[String]) -> SolveError {
+    SolveError::new(ErrorCode::CompileError, format!("Failed to compile code; exit code = {:?}", status))
+        .with_diagnostics(diagnostics::from_cargo_stderr(stderr))
 }
 
+struct PhaseTimer<'a> {
+    executor: &'a TestExecutor,
+    phase: &'static str,
+    start: std::time::Instant,
+}
 
-async fn tracing_execute(cmd: &mut Command, tests: &mut Vec<TTest>) -> anyhow::Result<ExitStatus> {
-    cmd.kill_on_drop(true);
-    tracing::debug!("Executing: {:?}", cmd);
-    let mut child = cmd
-        .stderr(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
-    let stderr = child.stderr.take().unwrap();
-    let stdout = child.stdout.take().unwrap();
-    tokio::spawn(async move {
-        let mut stderr = tokio::io::BufReader::new(stderr).lines();
-        while let Some(line) = stderr.next_line().await.unwrap() {
-            tracing::debug!("stderr: {line}");
-        }
-    });
-
-    let (tx, mut rx) = tokio::sync::mpsc::channel(10000);
-
-    tokio::spawn(async move {
-        let mut stdout = tokio::io::BufReader::new(stdout).lines();
-        while let Some(line) = stdout.next_line().await.unwrap() {
-            tracing::debug!("stdout: {line}");
-            tx.send(line).await.unwrap()
-        }
-    });
-    let status = child.wait().await?;
-    let test_results = test_results_from_stdout(&mut rx).await?;
-    tests.extend(test_results);
-    Ok(status)
+impl Drop for PhaseTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        metrics::PHASE_SECONDS.with_label_values(&[self.phase]).observe(elapsed.as_secs_f64());
+        self.executor.timings.lock().unwrap().push((self.phase, elapsed));
+    }
 }
 
-async fn test_results_from_stdout(rx: &mut tokio::sync::mpsc::Receiver<String>) -> anyhow::Result<Vec<TTest>> {
+fn test_results_from_stdout(stdout: &[String]) -> Vec<TTest> {
     let mut tests = Vec::new();
     let mut tests_failed = Vec::new();
     let mut collecting_key = String::new();
     let mut collected_lines = String::new();
-    while let Some(line) = rx.recv().await {
-        if let Some(captures) = TEST_RESULT_REGEX.captures(&line) {
+    for line in stdout {
+        if let Some(captures) = TEST_RESULT_REGEX.captures(line) {
             let test_name = captures.name("test_name").unwrap().as_str().to_string();
             let result = captures.name("result").unwrap().as_str();
             tracing::debug!("Detected test result: {test_name} -> {result}");
@@ -169,24 +341,183 @@ async fn test_results_from_stdout(rx: &mut tokio::sync::mpsc::Receiver<String>)
             tracing::trace!("Collecting starts for key: {collecting_key}");
         } else if line.is_empty() {
             if !collecting_key.is_empty() {
-                let test_name = std::mem::replace(&mut collecting_key, String::new());
-                let test_fail_details = std::mem::replace(&mut collected_lines, String::new());
+                let test_name = std::mem::take(&mut collecting_key);
+                let test_fail_details = std::mem::take(&mut collected_lines);
                 tracing::trace!("Collecting ends for key: {}", test_name);
                 let test = TTest::error(test_name, test_fail_details);
                 tests.push(test);
             }
-        } else if !collecting_key.is_empty() {
-            if line.contains("assert") {
-                tracing::trace!("Collecting line: {line}");
-                collected_lines.write_str(&line).unwrap();
-                collected_lines.write_str("\n").unwrap();
-            }
+        } else if !collecting_key.is_empty() && line.contains("assert") {
+            tracing::trace!("Collecting line: {line}");
+            collected_lines.write_str(line).unwrap();
+            collected_lines.write_str("\n").unwrap();
         }
     }
     tracing::debug!("Tests: {tests:?}");
-    Ok(tests)
+    tests
 }
 
 lazy_static!(
+    /// Executables built by `TestExecutor::prebuilt_tests`, by working directory and lesson, with the fingerprint of the lesson they were built from
+    static ref PREBUILT_TESTS: std::sync::Mutex<std::collections::HashMap<(std::path::PathBuf, String), (String, std::path::PathBuf)>> = Default::default();
     static ref TEST_RESULT_REGEX: regex::Regex = regex::Regex::new(r#"test (?P<test_name>[^ ]+) ... (?P<result>ok|FAILED)"#).unwrap();
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Replay;
+    use crate::types::TEditorFile;
+
+    fn executor(dir: &std::path::Path, lesson_slug: &str, recording: &str) -> TestExecutor {
+        let test_request = TTestRequest {
+            runner: "solana".to_string(),
+            r#type: None,
+            course_slug: "intro-to-solana".to_string(),
+            lesson_slug: lesson_slug.to_string(),
+            files: vec![TEditorFile { path: "lib.rs".to_string(), content: "// submitted".to_string() }],
+            image: None,
+        };
+        let backend = Replay::from_file(std::path::Path::new(&format!("tests/recordings/{recording}.json"))).unwrap();
+        TestExecutor::new(dir.to_path_buf(), test_request, Box::new(backend))
+    }
+
+    fn work_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("agora-executor-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Grade the transfer-lamports lesson with a recording, in a fresh working directory
+    async fn perform_test(recording: &str) -> (std::path::PathBuf, anyhow::Result<TestRun>) {
+        let dir = work_dir(recording);
+        let executor = executor(&dir, "02-transfer-lamports", recording);
+        let test_run = executor.perform_test(crate::COURSE.lesson("02-transfer-lamports").unwrap()).await;
+        let phases = executor.timings().into_iter().map(|(phase, _)| phase).collect::<Vec<_>>();
+        assert_eq!(&phases[..2], ["template", "warmup"]);
+        (dir, test_run)
+    }
+
+    fn solve_error(test_run: anyhow::Result<TestRun>) -> SolveError {
+        test_run.err().expect("grading fails").downcast::<SolveError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn replay_test_results() {
+        let (dir, test_run) = perform_test("failed").await;
+        let test_run = test_run.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("src/lib.rs")).unwrap(), "// submitted");
+        assert!(dir.join("tests/lesson_tests.rs").is_file());
+        assert_eq!(test_run.mode, ExecutionMode::Sbf);
+        assert_eq!(serde_json::to_value(&test_run.tests).unwrap(), serde_json::json!([
+            { "title": "test_lamport_balances", "passed": true },
+            { "title": "test_lamport_transfer", "passed": false, "error": "assertion `left == right` failed: Recipient did not receive the lamports\n" },
+        ]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn warm_up_per_lesson_kind() {
+        let dir = work_dir("warm-up");
+        let warmed_up = |lesson_slug: &'static str| {
+            let dir = dir.clone();
+            async move {
+                let executor = executor(&dir, lesson_slug, "passed");
+                executor.prepare(crate::COURSE.lesson(lesson_slug).unwrap()).await.unwrap();
+                executor.timings().iter().any(|(phase, _)| *phase == "warmup")
+            }
+        };
+        assert!(warmed_up("02-transfer-lamports").await);
+        assert!(!warmed_up("03-cpi").await);
+        assert!(warmed_up("anchor-counter").await);
+        assert!(warmed_up("02-transfer-lamports").await);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_compile_errors() {
+        let (dir, test_run) = perform_test("compile-error").await;
+        let err = solve_error(test_run);
+        assert_eq!(err.code, ErrorCode::CompileError);
+        assert_eq!(serde_json::to_value(&err.diagnostics).unwrap(), serde_json::json!([
+            { "severity": "error", "message": "cannot find value `lamports` in this scope", "file": "src/lib.rs", "line": 31, "column": 53 },
+        ]));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let (dir, test_run) = perform_test("test-compile-error").await;
+        let err = solve_error(test_run);
+        assert_eq!(err.code, ErrorCode::TestCompileError);
+        // the error is in `tests/lesson_tests.rs`, which has hidden tests
+        assert_eq!(serde_json::to_value(&err.diagnostics).unwrap(), serde_json::json!([
+            { "severity": "error", "message": "The tests of the lesson do not compile with the submitted code" },
+        ]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prebuilt_tests_are_reused() {
+        let dir = work_dir("prebuilt");
+        let lesson = crate::COURSE.lesson("04-sysvar").unwrap();
+        assert_eq!(lesson.meta.test_mode, Some(TestMode::Prebuilt));
+        for prebuilt in [false, true] {
+            let executor = executor(&dir, "04-sysvar", "prebuilt");
+            let test_run = executor.perform_test(lesson).await.unwrap();
+            assert_eq!(test_run.mode, ExecutionMode::Sbf);
+            assert_eq!(serde_json::to_value(&test_run.tests).unwrap(), serde_json::json!([{ "title": "test_sysvar", "passed": true }]));
+            let phases = executor.timings().into_iter().map(|(phase, _)| phase).collect::<Vec<_>>();
+            assert_eq!(phases.contains(&"prebuild"), !prebuilt);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn validator_client_tests() {
+        let dir = work_dir("validator");
+        let lesson = crate::COURSE.lesson("05-rpc-counter").unwrap();
+        let executor = executor(&dir, "05-rpc-counter", "validator");
+        let test_run = executor.perform_test(lesson).await.unwrap();
+        assert_eq!(test_run.mode, ExecutionMode::Sbf);
+        assert_eq!(serde_json::to_value(&test_run.tests).unwrap(), serde_json::json!([
+            { "title": "test_program_is_deployed", "passed": true },
+            { "title": "test_increment_across_slots", "passed": false, "error": "assertion `left == right` failed: The counter was not incremented in a later slot\n" },
+        ]));
+        let phases = executor.timings().into_iter().map(|(phase, _)| phase).collect::<Vec<_>>();
+        assert_eq!(&phases[phases.len() - 2..], ["validator", "test"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn validator_startup_failure() {
+        let dir = work_dir("validator-startup-failure");
+        let lesson = crate::COURSE.lesson("05-rpc-counter").unwrap();
+        let executor = executor(&dir, "05-rpc-counter", "validator-startup-failure");
+        let err = executor.perform_test(lesson).await.err().expect("the validator does not start");
+        let message = err.to_string();
+        assert!(message.contains("exit status: 1"), "{message}");
+        assert!(message.contains("Address already in use"), "{message}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_scenarios() {
+        let dir = work_dir("scenarios");
+        let lesson = crate::COURSE.lesson("01-introduction").unwrap();
+        let executor = executor(&dir, "01-introduction", "scenarios");
+        let test_run = executor.perform_test(lesson).await.unwrap();
+        assert_eq!(test_run.mode, ExecutionMode::Sbf);
+        // the payer pays the fee of the transaction
+        assert_eq!(serde_json::to_value(&test_run.tests).unwrap(), serde_json::json!([{
+            "title": "test_logging",
+            "passed": true,
+            "accounts": [{
+                "name": "payer",
+                "address": "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+                "changes": [{ "field": "lamports", "before": "1000000000000", "after": "999999995000" }],
+            }],
+        }]));
+        let job: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.join("target/scenarios.json")).unwrap()).unwrap();
+        assert_eq!(job["programName"], "solana_lesson_introduction");
+        assert_eq!(job["scenarios"][0]["transactions"][0]["instructions"][0]["data"], "0a0b0c0d0e");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+//! Liveness and readiness checks
+//!
+//! Liveness only tells that the process is responsive. Readiness verifies that submissions can actually be graded:
+//! the toolchain is installed, the warm cache was built, there is disk space left, and the lessons resolve offline.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::coordinator::Coordinator;
+use crate::lesson::{Course, Lesson, LessonKind, TestMode};
+use crate::toolchain::{ToolchainProfile, Toolchains};
+
+/// Readiness checks spawn several processes, so their result is reused for a while
+const READINESS_TTL: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static!(
+    static ref LAST_READINESS: tokio::sync::Mutex<Option<(Instant, Readiness)>> = tokio::sync::Mutex::new(None);
+    static ref PLATFORM_TOOLS_REGEX: regex::Regex = regex::Regex::new(r#"platform-tools (?P<version>v[0-9.]+)"#).unwrap();
+);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    /// Toolchain profile the check applies to
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub toolchain: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn from_result(name: &'static str, toolchain: &str, result: anyhow::Result<String>) -> Self {
+        let toolchain = toolchain.to_string();
+        match result {
+            Ok(detail) => Self { name, toolchain, ok: true, detail },
+            Err(err) => Self { name, toolchain, ok: false, detail: format!("{err:#}") },
+        }
+    }
+}
+
+/// Run all readiness checks, or return the recent result
+pub async fn readiness(course: &Course, toolchains: &Toolchains) -> Readiness {
+    if crate::shutdown::is_draining() {
+        let check = Check::from_result("shutdown", "", Err(anyhow::anyhow!("Runner is shutting down")));
+        return Readiness { ready: false, checks: vec![check] };
+    }
+    let mut last = LAST_READINESS.lock().await;
+    if let Some((at, readiness)) = last.as_ref() {
+        if at.elapsed() < READINESS_TTL {
+            return readiness.clone();
+        }
+    }
+    let readiness = check_readiness(course, toolchains).await;
+    if !readiness.ready {
+        tracing::warn!("Not ready: {readiness:?}");
+    }
+    *last = Some((Instant::now(), readiness.clone()));
+    readiness
+}
+
+/// A coordinator grades nothing itself; it is ready when every toolchain has a live worker
+pub fn coordinator_readiness(coordinator: &Coordinator, toolchains: &Toolchains) -> Readiness {
+    if crate::shutdown::is_draining() {
+        let check = Check::from_result("shutdown", "", Err(anyhow::anyhow!("Runner is shutting down")));
+        return Readiness { ready: false, checks: vec![check] };
+    }
+    let workers = coordinator.workers();
+    let checks = toolchains.profiles.iter()
+        .map(|toolchain| {
+            let slots = workers.iter()
+                .filter(|worker| worker.toolchains.contains(&toolchain.name))
+                .map(|worker| worker.slots)
+                .sum::<u32>();
+            let result = match slots {
+                0 => Err(anyhow::anyhow!("No worker registered")),
+                slots => Ok(format!("{slots} slot(s) on workers")),
+            };
+            Check::from_result("workers", &toolchain.name, result)
+        })
+        .collect::<Vec<_>>();
+    let ready = checks.iter().all(|check| check.ok);
+    Readiness { ready, checks }
+}
+
+async fn check_readiness(course: &Course, toolchains: &Toolchains) -> Readiness {
+    let mut checks = Vec::new();
+    for toolchain in &toolchains.profiles {
+        if let Some(recording) = &toolchain.replay {
+            let version = toolchain.version().await.map(|version| format!("Replaying {}: {version}", recording.display()));
+            checks.push(Check::from_result("toolchain", &toolchain.name, version));
+            continue;
+        }
+        let sbf_version = command_output(toolchain.cargo_cmd().args(["build-sbf", "--version"])).await;
+        let platform_tools = match &sbf_version {
+            Ok(version) => check_platform_tools(version),
+            Err(_) => Err(anyhow::anyhow!("cargo build-sbf is not available")),
+        };
+        let lessons = course.lessons_by_slug.values()
+            .filter(|lesson| toolchains.for_lesson(lesson).is_some_and(|profile| profile.name == toolchain.name))
+            .collect::<Vec<_>>();
+        checks.push(Check::from_result("toolchain", &toolchain.name, check_toolchain(&lessons, toolchain, sbf_version).await));
+        checks.push(Check::from_result("platform-tools", &toolchain.name, platform_tools));
+        checks.push(Check::from_result("warm-cache", &toolchain.name, check_warm_cache(&toolchain.warm_target)));
+        if lessons.iter().any(|lesson| lesson.meta.kind == LessonKind::Anchor) {
+            checks.push(Check::from_result("anchor-warm-cache", &toolchain.name, check_warm_cache(&toolchain.anchor_warm_target)));
+        }
+    }
+    checks.push(Check::from_result("disk-space", "", check_disk_space(Path::new("/tmp")).await));
+    checks.push(Check::from_result("lessons", "", check_lessons(course, toolchains).await));
+    let ready = checks.iter().all(|check| check.ok);
+    Readiness { ready, checks }
+}
+
+/// Versions of the tools of the profile, including those only some of its `lessons` need, and the SBF arguments
+/// they support
+async fn check_toolchain(lessons: &[&Lesson], toolchain: &ToolchainProfile, sbf_version: anyhow::Result<String>) -> anyhow::Result<String> {
+    let capabilities = toolchain.capabilities().await?;
+    if lessons.iter().any(|lesson| !lesson.meta.features.is_empty()) {
+        capabilities.require("--features")?;
+    }
+    let mut versions = vec![
+        command_output(toolchain.cargo_cmd().arg("--version")).await?,
+        sbf_version?,
+        format!("supports {}", capabilities.args.join(" ")),
+    ];
+    let needs_anchor = lessons.iter()
+        .any(|lesson| lesson.meta.kind == LessonKind::Anchor);
+    if needs_anchor {
+        versions.push(command_output(toolchain.command("anchor").arg("--version")).await?);
+    }
+    let needs_validator = lessons.iter()
+        .any(|lesson| lesson.meta.test_mode == Some(TestMode::Validator));
+    if needs_validator {
+        versions.push(command_output(toolchain.command("solana-test-validator").arg("--version")).await?);
+    }
+    Ok(versions.join("; ").replace('\n', ", "))
+}
+
+/// `cargo build-sbf --version` names the platform-tools it uses; they must be unpacked already, since builds are offline
+fn check_platform_tools(sbf_version: &str) -> anyhow::Result<String> {
+    let version = PLATFORM_TOOLS_REGEX.captures(sbf_version)
+        .map(|captures| captures["version"].to_string())
+        .ok_or_else(|| anyhow::anyhow!("Unknown platform-tools version in: {sbf_version}"))?;
+    let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("No home directory"))?;
+    let dir = home.join(".cache/solana").join(&version).join("platform-tools");
+    if !dir.is_dir() {
+        anyhow::bail!("platform-tools {version} not installed in {}", dir.display());
+    }
+    Ok(format!("{version} in {}", dir.display()))
+}
+
+fn check_warm_cache(target: &Path) -> anyhow::Result<String> {
+    let deploy = target.join("deploy");
+    let programs = std::fs::read_dir(&deploy)
+        .map_err(|err| anyhow::anyhow!("{} not built: {err}", deploy.display()))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "so"))
+        .count();
+    if programs == 0 {
+        anyhow::bail!("No program built in {}", deploy.display());
+    }
+    Ok(format!("{} program(s) in {}", programs, deploy.display()))
+}
+
+async fn check_disk_space(dir: &Path) -> anyhow::Result<String> {
+    let min_free_mb = std::env::var("AGORA_MIN_FREE_DISK_MB").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1024);
+    let df = command_output(Command::new("df").arg("-Pk").arg(dir)).await?;
+    let free_mb = free_mb_from_df(&df)?;
+    if free_mb < min_free_mb {
+        anyhow::bail!("Only {free_mb} MB free in {}, at least {min_free_mb} MB required", dir.display());
+    }
+    Ok(format!("{free_mb} MB free in {}", dir.display()))
+}
+
+/// Parse the `Available` column of `df -Pk`
+fn free_mb_from_df(df: &str) -> anyhow::Result<u64> {
+    let available_kb: u64 = df.lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .ok_or_else(|| anyhow::anyhow!("Unexpected df output: {df}"))?
+        .parse()?;
+    Ok(available_kb / 1024)
+}
+
+/// Every lesson must resolve its dependencies without network access, with the `cargo` of its toolchain
+///
+/// Lessons of toolchains that are not installed are skipped; the runner refuses to start with them, but a worker
+/// may have only some toolchains.
+async fn check_lessons(course: &Course, toolchains: &Toolchains) -> anyhow::Result<String> {
+    let mut failed = Vec::new();
+    for lesson in course.lessons_by_slug.values() {
+        let Some(toolchain) = toolchains.for_lesson(lesson) else {
+            continue;
+        };
+        let manifest = lesson.dir.join("Cargo.toml");
+        let tests = lesson.dir.join("tests/lesson_tests.rs");
+        if !tests.is_file() && lesson.scenarios.is_none() {
+            failed.push(format!("{}: missing {}", lesson.slug, tests.display()));
+            continue;
+        }
+        let mut metadata_cmd = toolchain.cargo_cmd();
+        metadata_cmd.args(["metadata", "--offline", "--format-version", "1", "--manifest-path"]).arg(&manifest);
+        if let Err(err) = command_output(&mut metadata_cmd).await {
+            failed.push(format!("{}: {err}", lesson.slug));
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("{}", failed.join("; "));
+    }
+    Ok(format!("{} lessons resolve offline", course.lessons_by_slug.len()))
+}
+
+/// Run a short command, returning its trimmed stdout; it is truncated, because the output of `cargo metadata` is large
+async fn command_output(cmd: &mut Command) -> anyhow::Result<String> {
+    let program = PathBuf::from(cmd.as_std().get_program());
+    let output = cmd.kill_on_drop(true).output().await
+        .map_err(|err| anyhow::anyhow!("{}: {err}", program.display()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+        anyhow::bail!("{} failed ({}): {}", program.display(), output.status, reason.trim());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.trim().chars().take(256).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_df_output() {
+        let df = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+                  overlay          61202244  31400552  26660440      55% /\n";
+        assert_eq!(free_mb_from_df(df).unwrap(), 26035);
+    }
+}
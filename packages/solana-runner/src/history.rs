@@ -0,0 +1,233 @@
+//! Optional history of graded submissions, kept in an SQLite database
+//!
+//! Enabled by pointing `AGORA_HISTORY_DB` to the database file; it is created when missing.
+
+use std::path::Path;
+
+use anyhow::Context;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use sha2::Digest;
+
+use crate::types::{TEditorFile, TTestResponse};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    request_id TEXT NOT NULL,
+    session_id TEXT,
+    course_slug TEXT NOT NULL,
+    lesson_slug TEXT NOT NULL,
+    created_at_ms INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    passed INTEGER NOT NULL,
+    error_code TEXT,
+    response TEXT NOT NULL,
+    timings TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS attempts_by_session ON attempts (session_id, id);
+CREATE TABLE IF NOT EXISTS attempt_files (
+    attempt_id INTEGER NOT NULL REFERENCES attempts (id),
+    path TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS attempt_files_by_attempt ON attempt_files (attempt_id);
+"#;
+
+pub struct History {
+    conn: std::sync::Mutex<rusqlite::Connection>,
+}
+
+/// A graded submission, as it is recorded
+pub struct NewAttempt<'a> {
+    pub request_id: &'a str,
+    pub session_id: Option<&'a str>,
+    pub course_slug: &'a str,
+    pub lesson_slug: &'a str,
+    pub files: &'a [TEditorFile],
+    pub response: &'a TTestResponse,
+    pub duration: std::time::Duration,
+    pub timings: &'a [(String, std::time::Duration)],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttemptSummary {
+    pub id: i64,
+    pub request_id: String,
+    pub session_id: Option<String>,
+    pub course_slug: String,
+    pub lesson_slug: String,
+    pub created_at_ms: i64,
+    pub duration_ms: i64,
+    pub passed: bool,
+    pub error_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attempt {
+    #[serde(flatten)]
+    pub summary: AttemptSummary,
+    pub files: Vec<AttemptFile>,
+    pub response: serde_json::Value,
+    /// Duration of the grading phases in milliseconds
+    pub timings: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttemptFile {
+    pub path: String,
+    pub sha256: String,
+    pub content: String,
+}
+
+impl History {
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var_os("AGORA_HISTORY_DB") {
+            Some(path) => Ok(Some(Self::open(Path::new(&path))?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("Cannot open history database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    fn init(conn: rusqlite::Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn: std::sync::Mutex::new(conn) })
+    }
+
+    /// Store the attempt, returning its id
+    pub fn record(&self, attempt: &NewAttempt) -> anyhow::Result<i64> {
+        let response = serde_json::to_string(attempt.response)?;
+        let timings = attempt.timings.iter()
+            .map(|(phase, duration)| (phase.clone(), serde_json::Value::from(duration.as_millis() as u64)))
+            .collect::<serde_json::Map<_, _>>();
+        let error_code = attempt.response.error_code().map(|code| code.to_string());
+        let created_at_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis() as i64;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO attempts (request_id, session_id, course_slug, lesson_slug, created_at_ms, duration_ms, passed, error_code, response, timings) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                attempt.request_id,
+                attempt.session_id,
+                attempt.course_slug,
+                attempt.lesson_slug,
+                created_at_ms,
+                attempt.duration.as_millis() as i64,
+                attempt.response.error_code().is_none(),
+                error_code,
+                response,
+                serde_json::Value::Object(timings).to_string(),
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for file in attempt.files {
+            let sha256 = hex::encode(sha2::Sha256::digest(file.content.as_bytes()));
+            tx.execute(
+                "INSERT INTO attempt_files (attempt_id, path, sha256, content) VALUES (?1, ?2, ?3, ?4)",
+                params![id, file.path, sha256, file.content],
+            )?;
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Attempts of a session, the most recent first
+    pub fn attempts(&self, session_id: &str) -> anyhow::Result<Vec<AttemptSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT id, request_id, session_id, course_slug, lesson_slug, created_at_ms, duration_ms, passed, error_code \
+             FROM attempts WHERE session_id = ?1 ORDER BY id DESC",
+        )?;
+        let attempts = statement.query_map(params![session_id], summary_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(attempts)
+    }
+
+    pub fn attempt(&self, id: i64) -> anyhow::Result<Option<Attempt>> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn.query_row(
+            "SELECT id, request_id, session_id, course_slug, lesson_slug, created_at_ms, duration_ms, passed, error_code, response, timings \
+             FROM attempts WHERE id = ?1",
+            params![id],
+            |row| Ok((summary_from_row(row)?, row.get::<_, String>(9)?, row.get::<_, String>(10)?)),
+        ).optional()?;
+        let Some((summary, response, timings)) = found else {
+            return Ok(None);
+        };
+        let mut statement = conn.prepare("SELECT path, sha256, content FROM attempt_files WHERE attempt_id = ?1 ORDER BY path")?;
+        let files = statement.query_map(params![id], |row| {
+            Ok(AttemptFile { path: row.get(0)?, sha256: row.get(1)?, content: row.get(2)? })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(Attempt {
+            summary,
+            files,
+            response: serde_json::from_str(&response)?,
+            timings: serde_json::from_str(&timings)?,
+        }))
+    }
+}
+
+fn summary_from_row(row: &rusqlite::Row) -> rusqlite::Result<AttemptSummary> {
+    Ok(AttemptSummary {
+        id: row.get(0)?,
+        request_id: row.get(1)?,
+        session_id: row.get(2)?,
+        course_slug: row.get(3)?,
+        lesson_slug: row.get(4)?,
+        created_at_ms: row.get(5)?,
+        duration_ms: row.get(6)?,
+        passed: row.get(7)?,
+        error_code: row.get(8)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ErrorCode, TTest};
+
+    #[test]
+    fn record_and_query_attempts() {
+        let history = History::init(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        let files = vec![TEditorFile { path: "lib.rs".to_string(), content: "fn main() {}".to_string() }];
+        let failed = TTestResponse::error(ErrorCode::CompileError, "Failed to compile code");
+        let passed = TTestResponse::from(vec![TTest::ok("test_sysvar")]);
+        for (request_id, response) in [("r1", &failed), ("r2", &passed)] {
+            history.record(&NewAttempt {
+                request_id,
+                session_id: Some("s1"),
+                course_slug: "intro-to-solana",
+                lesson_slug: "sysvar",
+                files: &files,
+                response,
+                duration: std::time::Duration::from_millis(1500),
+                timings: &[("build".to_string(), std::time::Duration::from_millis(1200))],
+            }).unwrap();
+        }
+
+        let attempts = history.attempts("s1").unwrap();
+        let summary = attempts.iter()
+            .map(|attempt| (attempt.request_id.as_str(), attempt.passed, attempt.error_code.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![("r2", true, None), ("r1", false, Some("compileError"))]);
+        assert!(history.attempts("s2").unwrap().is_empty());
+
+        let attempt = history.attempt(attempts[1].id).unwrap().unwrap();
+        assert_eq!(attempt.files[0].content, "fn main() {}");
+        assert_eq!(attempt.response["errorCode"], "compileError");
+        assert_eq!(attempt.timings["build"], 1200);
+        assert!(history.attempt(42).unwrap().is_none());
+    }
+}
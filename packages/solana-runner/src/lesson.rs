@@ -1,5 +1,10 @@
 use std::path::{Path, PathBuf};
 
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::scenario::Scenarios;
+
 #[derive(Debug)]
 pub struct Course {
     pub lessons_by_slug: std::collections::HashMap<String, Lesson>,
@@ -30,7 +35,9 @@ impl Course {
                     continue;
                 }
                 let lesson_slug = lesson_slug[prefix.len()..].to_string();
-                let lesson = Lesson { slug: lesson_slug, dir: path };
+                let meta = LessonMeta::from_dir(&path)?;
+                let scenarios = Scenarios::from_dir(&path)?;
+                let lesson = Lesson { slug: lesson_slug, dir: path, meta, scenarios };
                 tracing::debug!("Registering lesson: {lesson:?}");
                 lessons_by_slug.insert(lesson.slug.clone(), lesson);
             }
@@ -44,8 +51,172 @@ impl Course {
 
 }
 
+impl Lesson {
+    /// Name of the compiled program, i.e. the `.so` file in `target/deploy`, derived from the crate name
+    pub fn program_name(&self) -> anyhow::Result<String> {
+        let manifest = std::fs::read_to_string(self.dir.join("Cargo.toml"))?;
+        let captures = CRATE_NAME_REGEX.captures(&manifest)
+            .ok_or_else(|| anyhow::anyhow!("No crate name in {}", self.dir.join("Cargo.toml").display()))?;
+        Ok(captures["name"].replace('-', "_"))
+    }
+
+    /// Hash of the lesson template (tests, manifests, metadata), identifying its current version
+    ///
+    /// Computed on every call, so that edits to mounted lessons are noticed without a restart.
+    pub fn fingerprint(&self) -> anyhow::Result<String> {
+        use sha2::Digest;
+        let mut files = Vec::new();
+        collect_files(&self.dir, &mut files)?;
+        files.sort();
+        let mut hasher = sha2::Sha256::new();
+        for file in files {
+            let relative = file.strip_prefix(&self.dir)?;
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(std::fs::read(&file)?);
+            hasher.update([0]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+/// All files under `dir`, except build output
+pub fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name == "target") {
+                continue;
+            }
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+lazy_static::lazy_static!(
+    static ref CRATE_NAME_REGEX: regex::Regex = regex::Regex::new(r#"(?m)^name\s*=\s*"(?P<name>[^"]+)""#).unwrap();
+);
+
 #[derive(Clone, Debug)]
 pub struct Lesson {
     pub slug: String,
     pub dir: PathBuf,
+    pub meta: LessonMeta,
+    /// Declarative tests, replacing the `lesson_tests`
+    pub scenarios: Option<Scenarios>,
+}
+
+/// Optional lesson metadata, read from `lesson.json` in the lesson directory
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LessonMeta {
+    /// How the lesson project is built
+    #[serde(default)]
+    pub kind: LessonKind,
+    /// Names of tests that count toward pass/fail, but whose details are not disclosed to the student
+    #[serde(default)]
+    pub hidden_tests: Vec<String>,
+    /// How the lesson tests execute the program; when missing, `AGORA_TEST_MODE` applies; scenarios always run in the SBF VM
+    #[serde(default)]
+    pub test_mode: Option<TestMode>,
+    /// Address the program is deployed at by the `Validator` test mode
+    #[serde(default)]
+    pub program_id: Option<String>,
+    /// Name of the toolchain profile the lesson is written for, e.g. one with Agave 2.x for lessons pinning its crates;
+    /// when missing, the default profile; see `toolchain::Toolchains`
+    #[serde(default)]
+    pub toolchain: Option<String>,
+    /// Cargo features of the lesson crate, enabled when building and testing the program
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// What the warnings of the static analysis of the submission (see `analysis.rs`) do
+    #[serde(default)]
+    pub lints: Lints,
+}
+
+/// Kind of the lesson project template
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LessonKind {
+    /// Plain `solana_program` crate, built with `cargo build-sbf`
+    #[default]
+    Native,
+    /// Anchor program, built with `anchor build`; the IDL is generated as a separate step
+    Anchor,
+}
+
+/// What the warnings of the static analysis of a submission do
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Lints {
+    /// Reported as diagnostics next to the test results, without affecting pass/fail
+    #[default]
+    Warn,
+    /// Reported, and a submission with any of them fails
+    Deny,
+    /// The submission is not analyzed
+    Off,
+}
+
+/// How the lesson tests execute the student's program
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TestMode {
+    /// `cargo test-sbf`, leaving the choice to `solana-program-test`: the compiled `.so` if found, the `processor!` otherwise
+    #[default]
+    Auto,
+    /// `cargo test` on the host; the program runs natively through `processor!`
+    Native,
+    /// `cargo test-sbf` with the compiled `.so` required; the program runs in the SBF VM, like on-chain
+    Sbf,
+    /// Like `Sbf`, but the tests are built once per lesson version and run directly, so that a submission costs one
+    /// program build; the tests must load the program from its `.so` only (`ProgramTest::new(name, id, None)`),
+    /// without using the lesson crate
+    Prebuilt,
+    /// Like `Prebuilt`, but the tests are RPC clients: the program is deployed to a local `solana-test-validator`
+    /// started for the job, at the `programId` of the lesson; the tests find it through `AGORA_RPC_URL` and
+    /// `AGORA_PROGRAM_ID`, and the compiled program in `AGORA_PROGRAM_SO`, e.g. to deploy it again
+    Validator,
+}
+
+impl TestMode {
+    /// Runner-wide default, taken from the `AGORA_TEST_MODE` environment variable
+    pub fn from_env() -> Self {
+        let Ok(value) = std::env::var("AGORA_TEST_MODE") else {
+            return Self::default();
+        };
+        match serde_json::from_value(serde_json::Value::String(value.to_lowercase())) {
+            // needs lessons written for it, with a program id
+            Ok(Self::Validator) => {
+                tracing::warn!("Ignoring AGORA_TEST_MODE={value:?}; the validator mode is chosen by lessons only");
+                Self::default()
+            }
+            Ok(mode) => mode,
+            Err(_) => {
+                tracing::warn!("Ignoring invalid AGORA_TEST_MODE={value:?}; expected one of: auto, native, sbf, prebuilt");
+                Self::default()
+            }
+        }
+    }
+}
+
+impl LessonMeta {
+    pub const FILE_NAME: &'static str = "lesson.json";
+
+    pub fn from_dir(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(Self::FILE_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(&path)?;
+        let meta: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid lesson metadata in {}", path.display()))?;
+        if meta.test_mode == Some(TestMode::Validator) && meta.program_id.is_none() {
+            anyhow::bail!("Invalid lesson metadata in {}: the validator test mode needs a programId", path.display());
+        }
+        Ok(meta)
+    }
 }
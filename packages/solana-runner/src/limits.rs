@@ -0,0 +1,261 @@
+//! Rate limits and quotas, so that a single student cannot keep the runner busy
+//!
+//! * `AGORA_RATE_SESSION_PER_MINUTE`: submissions per minute of one session
+//! * `AGORA_RATE_IP_PER_MINUTE`: submissions per minute from one client address
+//! * `AGORA_QUOTA_CPU_SECS_PER_DAY`: grading seconds per session and UTC day
+//!
+//! A job holds one job slot, and with it the CPUs meant for one job, for as long as it runs; the CPU quota is
+//! therefore charged with the duration of the job's grading phases. Limits that are not set do not apply.
+//!
+//! A session the caller can make up, e.g. the `x-session-id` header of an unauthenticated request, does not count:
+//! such requests are limited as the session of their client address (see `limited_session` in `main.rs`).
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::types::ErrorCode;
+
+const WINDOW: Duration = Duration::from_secs(60);
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LimitsConfig {
+    pub session_per_minute: Option<usize>,
+    pub ip_per_minute: Option<usize>,
+    pub cpu_secs_per_day: Option<u64>,
+}
+
+pub struct Limits {
+    config: LimitsConfig,
+    state: std::sync::Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Admission times within the last `WINDOW`
+    by_session: HashMap<String, VecDeque<Instant>>,
+    by_ip: HashMap<IpAddr, VecDeque<Instant>>,
+    /// Grading time charged to the sessions today, by day number since the epoch
+    cpu_by_session: HashMap<String, (u64, Duration)>,
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub code: ErrorCode,
+    /// Which limit it was, for the metrics
+    pub limit: &'static str,
+    pub message: String,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub limits: LimitsConfig,
+    pub sessions: Vec<SessionUsage>,
+    /// Client addresses; not listed for callers restricted to their own session
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<ClientUsage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionUsage {
+    pub session_id: String,
+    pub submissions_last_minute: usize,
+    pub cpu_secs_today: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientUsage {
+    pub ip: IpAddr,
+    pub submissions_last_minute: usize,
+}
+
+fn env_limit<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self { config, state: Default::default() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(LimitsConfig {
+            session_per_minute: env_limit("AGORA_RATE_SESSION_PER_MINUTE"),
+            ip_per_minute: env_limit("AGORA_RATE_IP_PER_MINUTE"),
+            cpu_secs_per_day: env_limit("AGORA_QUOTA_CPU_SECS_PER_DAY"),
+        })
+    }
+
+    /// Count a submission, unless it exceeds one of the limits
+    pub fn admit(&self, session_id: Option<&str>, ip: Option<IpAddr>) -> Result<(), LimitExceeded> {
+        self.admit_at(session_id, ip, Instant::now(), unix_secs())
+    }
+
+    fn admit_at(&self, session_id: Option<&str>, ip: Option<IpAddr>, now: Instant, unix_secs: u64) -> Result<(), LimitExceeded> {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now, unix_secs / DAY_SECS);
+
+        if let (Some(session_id), Some(quota)) = (session_id, self.config.cpu_secs_per_day) {
+            let used = state.cpu_by_session.get(session_id).map(|(_, used)| *used).unwrap_or_default();
+            if used.as_secs() >= quota {
+                return Err(LimitExceeded {
+                    code: ErrorCode::QuotaExceeded,
+                    limit: "cpu_per_day",
+                    message: format!("Daily quota of {quota} grading seconds is used up"),
+                    retry_after: Duration::from_secs(DAY_SECS - unix_secs % DAY_SECS),
+                });
+            }
+        }
+        let session_id = session_id.zip(self.config.session_per_minute);
+        if let Some((session_id, limit)) = session_id {
+            check_window(state.by_session.get(session_id), limit, now, "session_per_minute")?;
+        }
+        let ip = ip.zip(self.config.ip_per_minute);
+        if let Some((ip, limit)) = ip {
+            check_window(state.by_ip.get(&ip), limit, now, "ip_per_minute")?;
+        }
+
+        // only admitted submissions count
+        if let Some((session_id, _)) = session_id {
+            state.by_session.entry(session_id.to_string()).or_default().push_back(now);
+        }
+        if let Some((ip, _)) = ip {
+            state.by_ip.entry(ip).or_default().push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Charge the grading time of a job to the session's daily quota
+    pub fn charge(&self, session_id: Option<&str>, grading_time: Duration) {
+        let (Some(session_id), Some(_)) = (session_id, self.config.cpu_secs_per_day) else {
+            return;
+        };
+        let today = unix_secs() / DAY_SECS;
+        let mut state = self.state.lock().unwrap();
+        let (day, used) = state.cpu_by_session.entry(session_id.to_string()).or_insert((today, Duration::ZERO));
+        if *day != today {
+            *day = today;
+            *used = Duration::ZERO;
+        }
+        *used += grading_time;
+    }
+
+    /// Current usage, of all sessions and clients or only of the given session
+    pub fn usage(&self, only_session: Option<&str>) -> Usage {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.prune(now, unix_secs() / DAY_SECS);
+        let mut session_ids = state.by_session.keys().chain(state.cpu_by_session.keys())
+            .filter(|session_id| only_session.is_none_or(|only| only == session_id.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        session_ids.sort();
+        session_ids.dedup();
+        let sessions = session_ids.into_iter()
+            .map(|session_id| SessionUsage {
+                submissions_last_minute: state.by_session.get(&session_id).map_or(0, VecDeque::len),
+                cpu_secs_today: state.cpu_by_session.get(&session_id).map_or(0, |(_, used)| used.as_secs()),
+                session_id,
+            })
+            .collect();
+        let clients = match only_session {
+            Some(_) => Vec::new(),
+            None => state.by_ip.iter()
+                .map(|(ip, window)| ClientUsage { ip: *ip, submissions_last_minute: window.len() })
+                .collect(),
+        };
+        Usage { limits: self.config, sessions, clients }
+    }
+}
+
+impl State {
+    /// Forget submissions outside of the window, and quotas of past days
+    fn prune(&mut self, now: Instant, today: u64) {
+        for window in self.by_session.values_mut().chain(self.by_ip.values_mut()) {
+            while window.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+                window.pop_front();
+            }
+        }
+        self.by_session.retain(|_, window| !window.is_empty());
+        self.by_ip.retain(|_, window| !window.is_empty());
+        self.cpu_by_session.retain(|_, (day, _)| *day == today);
+    }
+}
+
+fn check_window(window: Option<&VecDeque<Instant>>, limit: usize, now: Instant, name: &'static str) -> Result<(), LimitExceeded> {
+    let Some(window) = window else {
+        return Ok(());
+    };
+    if window.len() < limit {
+        return Ok(());
+    }
+    // the oldest submission within the limit has to leave the window first
+    let oldest = window[window.len() - limit];
+    let retry_after = WINDOW.saturating_sub(now.duration_since(oldest)).max(Duration::from_secs(1));
+    Err(LimitExceeded {
+        code: ErrorCode::RateLimited,
+        limit: name,
+        message: format!("Too many submissions, at most {limit} per minute are allowed"),
+        retry_after,
+    })
+}
+
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits() {
+        let limits = Limits::new(LimitsConfig { session_per_minute: Some(2), ip_per_minute: Some(3), cpu_secs_per_day: None });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(limits.admit_at(Some("s1"), Some(ip), at(0), 0).is_ok());
+        assert!(limits.admit_at(Some("s1"), Some(ip), at(10), 0).is_ok());
+        let exceeded = limits.admit_at(Some("s1"), Some(ip), at(20), 0).unwrap_err();
+        assert_eq!(exceeded.code, ErrorCode::RateLimited);
+        assert_eq!(exceeded.retry_after, Duration::from_secs(40));
+
+        // the rejected submission did not count against the address
+        assert!(limits.admit_at(Some("s2"), Some(ip), at(30), 0).is_ok());
+        assert_eq!(limits.admit_at(Some("s3"), Some(ip), at(30), 0).unwrap_err().limit, "ip_per_minute");
+
+        assert!(limits.admit_at(Some("s1"), None, at(61), 0).is_ok());
+    }
+
+    #[test]
+    fn daily_quota() {
+        let limits = Limits::new(LimitsConfig { cpu_secs_per_day: Some(100), ..Default::default() });
+        let now = unix_secs();
+        assert!(limits.admit_at(Some("s1"), None, Instant::now(), now).is_ok());
+        limits.charge(Some("s1"), Duration::from_secs(120));
+        let exceeded = limits.admit_at(Some("s1"), None, Instant::now(), now).unwrap_err();
+        assert_eq!(exceeded.code, ErrorCode::QuotaExceeded);
+        assert!(exceeded.retry_after <= Duration::from_secs(DAY_SECS));
+        assert!(limits.admit_at(Some("s2"), None, Instant::now(), now).is_ok());
+        assert!(limits.admit_at(None, None, Instant::now(), now).is_ok());
+
+        let usage = limits.usage(Some("s1"));
+        assert_eq!(usage.sessions.len(), 1);
+        assert_eq!(usage.sessions[0].cpu_secs_today, 120);
+
+        // quotas reset the next day
+        assert!(limits.admit_at(Some("s1"), None, Instant::now(), now + DAY_SECS).is_ok());
+    }
+}
@@ -1,32 +1,123 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use axum::{
-    Json,
+    Extension, Json,
     Router, routing::post, routing::get,
+    body::Body,
+    extract::{ConnectInfo, Path, Request},
+    http::{HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+use clap::Parser;
+use tracing::Instrument;
 
-use types::{TTestRequest, TTestResponse};
+use types::{ErrorCode, TSandboxRequest, TSandboxResponse, TTest, TTestRequest, TTestResponse, TToolchain};
 
 mod types;
 mod lesson;
+mod scenario;
+mod account_diff;
+mod sandbox;
 mod executor;
+mod backend;
+mod validator;
+mod analysis;
+mod diagnostics;
+mod metrics;
+mod health;
+mod api;
+mod toolchain;
+mod cache;
+mod history;
+mod auth;
+mod limits;
+mod shutdown;
+mod slots;
+mod coordinator;
+mod worker;
+mod cli;
 
 lazy_static::lazy_static!(
     pub static ref COURSE: lesson::Course = {
         let basedir = PathBuf::from("lessons-code");
         lesson::Course::from_dir(&basedir, "solana-").unwrap()
     };
+
+    pub static ref TOOLCHAINS: toolchain::Toolchains = toolchain::Toolchains::from_env().unwrap();
+
+    pub static ref RESULT_CACHE: cache::ResultCache = cache::ResultCache::from_env();
+
+    pub static ref AUTH: auth::Auth = auth::Auth::from_env().unwrap();
+
+    pub static ref LIMITS: limits::Limits = limits::Limits::from_env();
+
+    /// Take the client address from `X-Forwarded-For`, when the runner is behind a trusted proxy
+    pub static ref TRUST_FORWARDED_FOR: bool = std::env::var("AGORA_TRUST_FORWARDED_FOR").is_ok_and(|value| value == "1");
+
+    /// Address the server listens on; port 0 picks a free one, e.g. in tests
+    pub static ref LISTEN: String = std::env::var("AGORA_LISTEN").unwrap_or_else(|_| "0.0.0.0:7005".to_string());
+
+    /// How the runner is deployed: `standalone` (the default), `coordinator` or `worker`
+    pub static ref MODE: String = std::env::var("AGORA_MODE").unwrap_or_else(|_| "standalone".to_string());
+
+    /// Jobs queued for the workers, in the `coordinator` mode
+    pub static ref COORDINATOR: Option<coordinator::Coordinator> = (*MODE == "coordinator").then(Default::default);
+
+    /// Graded submissions, when `AGORA_HISTORY_DB` is set
+    pub static ref HISTORY: Option<history::History> = history::History::from_env().unwrap();
+
+    /// Limits how many submissions are graded at once; the rest wait in the queue
+    ///
+    /// A build of a Solana program takes the cores and memory of a whole runner, and concurrent builds wait for each
+    /// other on the cargo package cache lock anyway; so the default is a single slot, and `AGORA_MAX_JOBS` raises it
+    /// on bigger machines. Each slot builds in a working directory of its own, which is warmed up on its first job.
+    pub static ref JOB_SLOTS: slots::JobSlots = slots::JobSlots::new(*MAX_JOBS);
+
+    pub static ref MAX_JOBS: u32 = std::env::var("AGORA_MAX_JOBS").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1);
+
+    /// How many submissions may wait for a job slot, before new ones are turned away as `RunnerBusy`
+    pub static ref MAX_QUEUE: i64 = std::env::var("AGORA_MAX_QUEUE").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(8);
+
+    /// Grading a submission takes at most this long; then its processes are killed
+    pub static ref JOB_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(
+        std::env::var("AGORA_JOB_TIMEOUT_SECS").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(300)
+    );
+
+    /// On shutdown, running jobs get this long to finish before they are cancelled
+    pub static ref SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(
+        std::env::var("AGORA_SHUTDOWN_GRACE_SECS").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(60)
+    );
 );
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // initialize tracing
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = cli::Cli::parse();
+    // initialize tracing; commands print their results to stdout, so they log less, and to stderr
+    let (default_log, writer) = match cli.command {
+        Some(_) => ("warn", tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr)),
+        None => ("info", tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stdout)),
+    };
     let logvar = std::env::var("AGORA_LOG")
-        .unwrap_or_else(|_| "info".to_string());
-    tracing_subscriber::fmt()
+        .unwrap_or_else(|_| default_log.to_string());
+    let subscriber = tracing_subscriber::fmt()
         // .with_env_filter("agorapp=debug")
         .with_env_filter(logvar)
-        .init();
+        .with_writer(writer);
+    match std::env::var("AGORA_LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().with_current_span(true).with_span_list(false).init(),
+        _ => subscriber.init(),
+    }
 
     let lesson_count = COURSE.lessons_by_slug.len();
     if lesson_count == 0 {
@@ -34,63 +125,733 @@ async fn main() -> anyhow::Result<()> {
         anyhow::bail!("No lessons found in {}", COURSE.basedir.display())
     }
     tracing::info!("Registered {} lessons from directory {}", lesson_count, COURSE.basedir.display());
+    let toolchain_names = TOOLCHAINS.profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>();
+    tracing::info!("Toolchain profiles: {}", toolchain_names.join(", "));
+    let missing = TOOLCHAINS.missing(&COURSE);
+    if !missing.is_empty() {
+        let missing = missing.iter()
+            .map(|(lesson_slug, toolchain)| format!("{lesson_slug} needs {toolchain}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // a worker grades the lessons of the toolchains it has, and the coordinator sends it only those
+        if MODE.as_str() != "worker" {
+            anyhow::bail!("Toolchain profiles missing: {missing}");
+        }
+        tracing::warn!("Toolchain profiles missing, not grading their lessons: {missing}");
+    }
+    if !AUTH.enabled() && cli.command.is_none() {
+        tracing::warn!("Authentication is not configured, anyone can submit solutions");
+    }
+    if HISTORY.is_some() {
+        tracing::info!("Recording submission history");
+    }
+    metrics::init();
+    if let Some(cli::Command::Solve(args)) = cli.command {
+        let passed = cli::solve(args).await?;
+        return Ok(if passed { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+    // a coordinator runs no tools; its workers probe theirs
+    if MODE.as_str() != "coordinator" {
+        for toolchain in &TOOLCHAINS.profiles {
+            match toolchain.capabilities().await {
+                Ok(capabilities) => tracing::info!("Toolchain {}: {:?}, supporting {}", toolchain.name, capabilities.versions, capabilities.args.join(" ")),
+                Err(err) => tracing::warn!("Cannot probe toolchain {}: {err:#}", toolchain.name),
+            }
+        }
+    }
+    match MODE.as_str() {
+        "standalone" | "coordinator" => {}
+        "worker" => {
+            let coordinator_url = std::env::var("AGORA_COORDINATOR_URL")
+                .map_err(|_| anyhow::anyhow!("AGORA_COORDINATOR_URL is required in the worker mode"))?;
+            tracing::info!("Grading jobs of the coordinator at {coordinator_url}");
+            tokio::spawn(drain());
+            worker::run(&coordinator_url).await?;
+            clean_work_dirs();
+            tracing::info!("Stopped");
+            return Ok(ExitCode::SUCCESS);
+        }
+        mode => anyhow::bail!("Unknown AGORA_MODE: {mode}"),
+    }
+    if let Some(coordinator) = COORDINATOR.as_ref() {
+        tracing::info!("Dispatching jobs to workers");
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(coordinator::HEARTBEAT_INTERVAL).await;
+                coordinator.reap();
+            }
+        });
+    }
     // build our application with a route
+    // routes that start work or expose submissions require authentication
+    let authenticated = Router::new()
+        .route("/v1/solve", post(solve_v1))
+        .route("/v1/solve/", post(solve_v1))
+        .route("/v2/solve", post(solve))
+        .route("/v2/sandbox", post(sandbox))
+        .route("/v1/sessions/:session_id/attempts", get(session_attempts))
+        .route("/v1/attempts/:id", get(attempt))
+        .route("/v1/status/usage", get(usage))
+        .route("/v1/status/workers", get(workers))
+        .route("/internal/workers", post(register_worker))
+        .route("/internal/workers/:worker_id", axum::routing::delete(deregister_worker))
+        .route("/internal/workers/:worker_id/heartbeat", post(worker_heartbeat))
+        .route("/internal/workers/:worker_id/jobs/next", get(next_job))
+        .route("/internal/workers/:worker_id/jobs/:job_id/result", post(job_result))
+        .route_layer(axum::middleware::from_fn(authenticate));
     let app = Router::new()
         // `POST /users` goes to `create_user`
         .route("/v1/status", get(health))
         .route("/v1/status/", get(health))
-        .route("/v1/solve", post(solve))
-        .route("/v1/solve/", post(solve))
+        .route("/v1/status/toolchains", get(toolchains))
+        .route("/v1/health/live", get(live))
+        .route("/v1/health/ready", get(ready))
+        .route("/v2/openapi.json", get(openapi))
+        .route("/v2/schemas/:name", get(schema))
+        .route("/metrics", get(prometheus_metrics))
+        .merge(authenticated)
         ;
 
     // run our app with hyper
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:7005")
+    let listener = tokio::net::TcpListener::bind(LISTEN.as_str())
         .await
         .unwrap();
     tracing::info!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await?;
-    Ok(())
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(drain())
+        .await?;
+
+    clean_work_dirs();
+    tracing::info!("Stopped");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn clean_work_dirs() {
+    for toolchain in &TOOLCHAINS.profiles {
+        if toolchain.work_dir.exists() {
+            if let Err(err) = std::fs::remove_dir_all(&toolchain.work_dir) {
+                tracing::warn!("Cannot remove {}: {err}", toolchain.work_dir.display());
+            }
+        }
+    }
+}
+
+/// Resolves when the runner is ready to stop: after a shutdown signal, once the running jobs finished or were cancelled
+///
+/// Until then, the server keeps answering, so that the failing readiness can be observed.
+async fn drain() {
+    shutdown::signal().await;
+    shutdown::start_draining();
+    tracing::info!("Draining, waiting up to {:?} for running jobs", *SHUTDOWN_GRACE);
+    let idle = async {
+        match COORDINATOR.as_ref() {
+            Some(coordinator) => coordinator.idle().await,
+            // all job slots are free once the running jobs are done; the queued ones give up their slot, see `grade`
+            None => JOB_SLOTS.idle().await,
+        }
+    };
+    match tokio::time::timeout(*SHUTDOWN_GRACE, idle).await {
+        Ok(_) => tracing::info!("All jobs finished"),
+        Err(_) => {
+            tracing::warn!("Cancelling {} running job(s)", metrics::ACTIVE_JOBS.get());
+            shutdown::cancel_jobs();
+        }
+    }
 }
 
 async fn health() -> &'static str {
     "OK"
 }
 
-async fn solve(Json(test_request): Json<TTestRequest>) -> Json<TTestResponse> {
+async fn live() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "live": true }))
+}
+
+/// The toolchain profiles with the versions of their tools; those that cannot be probed have no versions
+async fn toolchains() -> Json<Vec<TToolchain>> {
+    let mut toolchains = Vec::new();
+    for toolchain in &TOOLCHAINS.profiles {
+        let described = match COORDINATOR.as_ref() {
+            Some(_) => None,
+            None => toolchain.describe().await
+                .map_err(|err| tracing::warn!("Cannot describe toolchain {}: {err:#}", toolchain.name))
+                .ok(),
+        };
+        toolchains.push(described.unwrap_or_else(|| TToolchain { name: toolchain.name.clone(), versions: Default::default() }));
+    }
+    Json(toolchains)
+}
+
+async fn ready() -> (StatusCode, Json<health::Readiness>) {
+    let readiness = match COORDINATOR.as_ref() {
+        Some(coordinator) => health::coordinator_readiness(coordinator, &TOOLCHAINS),
+        None => health::readiness(&COURSE, &TOOLCHAINS).await,
+    };
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness))
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(http::header::CONTENT_TYPE, "application/json")], api::openapi_json())
+}
+
+async fn schema(Path(name): Path<String>) -> Result<Json<serde_json::Value>, StatusCode> {
+    api::schema_json(&name)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Largest request body accepted; the same as the default limit of the `Json` extractor
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Reject requests without valid credentials before any work is done, and pass the `auth::Principal` to the handler
+async fn authenticate(request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+    };
+    match AUTH.authenticate(&parts.method, &parts.uri, &parts.headers, &body) {
+        Ok(principal) => {
+            let mut request = Request::from_parts(parts, Body::from(body));
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(err) => {
+            tracing::warn!("Rejected {} {}: {err}", parts.method, parts.uri.path());
+            auth_error_response(err)
+        }
+    }
+}
+
+fn auth_error_response(err: auth::AuthError) -> Response {
+    let response = TTestResponse::error(err.code, err.message);
+    (response.status(), Json(response)).into_response()
+}
+
+/// Rate limit and quota usage; callers restricted to a session see only their own
+async fn usage(Extension(principal): Extension<auth::Principal>) -> Json<limits::Usage> {
+    Json(LIMITS.usage(principal.session_id.as_deref()))
+}
+
+fn coordinator() -> Result<&'static coordinator::Coordinator, (StatusCode, &'static str)> {
+    COORDINATOR.as_ref().ok_or((StatusCode::NOT_FOUND, "Not a coordinator"))
+}
+
+async fn workers(Extension(principal): Extension<auth::Principal>) -> Result<Json<Vec<coordinator::WorkerStatus>>, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    Ok(Json(coordinator().map_err(IntoResponse::into_response)?.workers()))
+}
+
+async fn register_worker(Extension(principal): Extension<auth::Principal>, Json(registration): Json<coordinator::Registration>) -> Result<Json<coordinator::Registered>, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    Ok(Json(coordinator().map_err(IntoResponse::into_response)?.register(registration)))
+}
+
+async fn deregister_worker(Extension(principal): Extension<auth::Principal>, Path(worker_id): Path<String>) -> Result<StatusCode, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    coordinator().map_err(IntoResponse::into_response)?.deregister(&worker_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn unknown_worker(_: coordinator::UnknownWorker) -> Response {
+    (StatusCode::NOT_FOUND, "Unknown worker, register again").into_response()
+}
+
+async fn worker_heartbeat(Extension(principal): Extension<auth::Principal>, Path(worker_id): Path<String>) -> Result<StatusCode, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    coordinator().map_err(IntoResponse::into_response)?.heartbeat(&worker_id).map_err(unknown_worker)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn next_job(Extension(principal): Extension<auth::Principal>, Path(worker_id): Path<String>) -> Result<Response, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    let job = coordinator().map_err(IntoResponse::into_response)?.next_job(&worker_id, coordinator::POLL_TIMEOUT).await.map_err(unknown_worker)?;
+    Ok(match job {
+        Some(job) => Json(job).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+async fn job_result(
+    Extension(principal): Extension<auth::Principal>,
+    Path((worker_id, job_id)): Path<(String, u64)>,
+    Json(result): Json<coordinator::JobResult>,
+) -> Result<StatusCode, Response> {
+    principal.authorize_internal().map_err(auth_error_response)?;
+    coordinator().map_err(IntoResponse::into_response)?.complete(&worker_id, job_id, result).map_err(unknown_worker)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Address of the client, or of the proxy in front of the runner, unless it is trusted to forward it
+fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> std::net::IpAddr {
+    let forwarded = TRUST_FORWARDED_FOR.then(|| {
+        let value = headers.get("x-forwarded-for")?.to_str().ok()?;
+        value.split(',').next()?.trim().parse().ok()
+    });
+    forwarded.flatten().unwrap_or(peer.ip())
+}
+
+/// Session the per-session limits and the daily quota are charged to
+///
+/// With authentication, the session is the one of the platform JWT, or the `x-session-id` header that our own services
+/// vouch for. Without it, the client picks the header, and could skip the limits by leaving it out or reset them by
+/// changing it; so such requests, like authenticated ones without a session, are charged to the client address, as
+/// `ip:<address>`.
+fn limited_session(session_id: Option<&str>, ip: std::net::IpAddr) -> String {
+    match session_id {
+        Some(session_id) if AUTH.enabled() => session_id.to_string(),
+        _ => format!("ip:{ip}"),
+    }
+}
+
+async fn session_attempts(Extension(principal): Extension<auth::Principal>, Path(session_id): Path<String>) -> Result<Json<Vec<history::AttemptSummary>>, Response> {
+    principal.authorize_session(Some(&session_id)).map_err(auth_error_response)?;
+    let history = HISTORY.as_ref().ok_or((StatusCode::NOT_FOUND, "History is not enabled").into_response())?;
+    history.attempts(&session_id)
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+}
+
+async fn attempt(Extension(principal): Extension<auth::Principal>, Path(id): Path<i64>) -> Result<Json<history::Attempt>, Response> {
+    let history = HISTORY.as_ref().ok_or((StatusCode::NOT_FOUND, "History is not enabled").into_response())?;
+    match history.attempt(id) {
+        Ok(Some(attempt)) => {
+            principal.authorize_session(attempt.summary.session_id.as_deref()).map_err(auth_error_response)?;
+            Ok(Json(attempt))
+        }
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Attempt not found: {id}")).into_response()),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()),
+    }
+}
+
+async fn prometheus_metrics() -> Result<String, (StatusCode, String)> {
+    metrics::render()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Correlates the logs of one submission; taken from the request if the caller provides it, and always returned
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+static SESSION_ID_HEADER: HeaderName = HeaderName::from_static("x-session-id");
+
+/// Value of a correlation header, if present and safe to put in logs
+fn correlation_header(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let valid = !value.is_empty() && value.len() <= 128
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    valid.then(|| value.to_string())
+}
+
+/// Build the submitted program and run the lesson tests against it
+#[utoipa::path(
+    post,
+    path = "/v2/solve",
+    tag = "solve",
+    request_body = TTestRequest,
+    params(
+        ("authorization" = Option<String>, Header, description = "`Bearer` with the pre-shared token or a platform JWT, when the runner requires authentication"),
+        ("x-agora-timestamp" = Option<String>, Header, description = "Unix time of a signed request"),
+        ("x-agora-signature" = Option<String>, Header, description = "Hex HMAC-SHA256 of `<x-agora-timestamp>.<METHOD>.<path and query>.<body>`"),
+        ("x-request-id" = Option<String>, Header, description = "Correlation id; generated when missing, and always returned"),
+        ("x-session-id" = Option<String>, Header, description = "Student session, for the logs, the submission history and, when the runner requires authentication, the limits"),
+    ),
+    responses(
+        (status = 200, description = "Tests were run; `passed` tells whether all of them passed", body = TTestResponse),
+        (status = 400, description = "`invalidSubmission`", body = TTestResponse),
+        (status = 401, description = "`unauthorized`", body = TTestResponse),
+        (status = 403, description = "`forbidden`", body = TTestResponse),
+        (status = 404, description = "`unknownLesson`", body = TTestResponse),
+        (status = 422, description = "`compileError` or `testCompileError`", body = TTestResponse),
+        (status = 429, description = "`rateLimited` or `quotaExceeded`, with `Retry-After`", body = TTestResponse),
+        (status = 500, description = "`internalError`", body = TTestResponse),
+        (status = 503, description = "`runnerBusy` or `runnerRestarting`", body = TTestResponse),
+        (status = 504, description = "`timeout`", body = TTestResponse),
+    ),
+)]
+async fn solve(
+    Extension(principal): Extension<auth::Principal>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(test_request): Json<TTestRequest>,
+) -> Response {
+    let (request_id, response) = solve_request(principal, peer, &headers, test_request).await;
+    json_response(request_id, response.status(), response.retry_after(), response)
+}
+
+/// Like `solve`, but with the status of the first protocol version: `200` whenever there is a `TTestResponse`, even
+/// one with an `errorCode`, since clients of `/v1` take any other status for a failure of the runner
+async fn solve_v1(
+    Extension(principal): Extension<auth::Principal>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(test_request): Json<TTestRequest>,
+) -> Response {
+    let (request_id, response) = solve_request(principal, peer, &headers, test_request).await;
+    json_response(request_id, StatusCode::OK, response.retry_after(), response)
+}
+
+/// Grade a submission within the limits of its caller, and record it in the history; returns the request id with
+/// the response
+async fn solve_request(
+    principal: auth::Principal,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    test_request: TTestRequest,
+) -> (String, TTestResponse) {
+    let request_id = correlation_header(headers, &REQUEST_ID_HEADER)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if let Err(err) = principal.authorize_course(&test_request.course_slug) {
+        tracing::warn!("Rejected solve: {err}");
+        return (request_id, TTestResponse::error(err.code, err.message));
+    }
+    let session_id = principal.session_id
+        .or_else(|| correlation_header(headers, &SESSION_ID_HEADER));
+    let span = tracing::info_span!("solve",
+        request_id = %request_id,
+        session_id = %session_id.as_deref().unwrap_or("-"),
+        course = %test_request.course_slug,
+        lesson = %test_request.lesson_slug,
+    );
+    // the request is consumed by grading, so keep what goes to the history
+    let submission = HISTORY.as_ref().map(|_| {
+        (test_request.course_slug.clone(), test_request.lesson_slug.clone(), test_request.files.clone())
+    });
+    let ip = client_ip(headers, peer);
+    let limited = limited_session(session_id.as_deref(), ip);
+    if let Err(exceeded) = LIMITS.admit(Some(&limited), Some(ip)) {
+        span.in_scope(|| tracing::warn!("Rejected solve: {}", exceeded.message));
+        metrics::RATE_LIMITED.with_label_values(&[exceeded.limit]).inc();
+        let response = TTestResponse::error(exceeded.code, exceeded.message).with_retry_after(exceeded.retry_after);
+        return (request_id, response);
+    }
+    let start = std::time::Instant::now();
+    let (response, timings) = solve_measured(test_request).instrument(span.clone()).await;
+    LIMITS.charge(Some(&limited), timings.iter().map(|(_, duration)| *duration).sum());
+    if let (Some(history), Some((course_slug, lesson_slug, files))) = (HISTORY.as_ref(), submission) {
+        let attempt = history::NewAttempt {
+            request_id: &request_id,
+            session_id: session_id.as_deref(),
+            course_slug: &course_slug,
+            lesson_slug: &lesson_slug,
+            files: &files,
+            response: &response,
+            duration: start.elapsed(),
+            timings: &timings,
+        };
+        if let Err(err) = history.record(&attempt) {
+            span.in_scope(|| tracing::error!("Cannot record the attempt: {err:#}"));
+        }
+    }
+    (request_id, response)
+}
+
+fn json_response(request_id: String, status: StatusCode, retry_after: Option<u64>, body: impl serde::Serialize) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(REQUEST_ID_HEADER.clone(), request_id.parse().expect("request ids are valid header values"));
+    if let Some(retry_after) = retry_after {
+        headers.insert(http::header::RETRY_AFTER, retry_after.into());
+    }
+    (status, headers, Json(body)).into_response()
+}
+
+/// Build the submitted program, and run the given transactions against it in a fresh local bank; nothing is graded
+///
+/// Standalone runners only: a coordinator does not hand sandbox requests to its workers.
+#[utoipa::path(
+    post,
+    path = "/v2/sandbox",
+    tag = "sandbox",
+    request_body = TSandboxRequest,
+    params(
+        ("authorization" = Option<String>, Header, description = "`Bearer` with the pre-shared token or a platform JWT, when the runner requires authentication"),
+        ("x-request-id" = Option<String>, Header, description = "Correlation id; generated when missing, and always returned"),
+        ("x-session-id" = Option<String>, Header, description = "Student session, for the logs and, when the runner requires authentication, the limits"),
+    ),
+    responses(
+        (status = 200, description = "The transactions were sent; each tells whether it failed", body = TSandboxResponse),
+        (status = 400, description = "`invalidSubmission`, also for accounts that cannot be set up", body = TSandboxResponse),
+        (status = 401, description = "`unauthorized`", body = TSandboxResponse),
+        (status = 403, description = "`forbidden`", body = TSandboxResponse),
+        (status = 404, description = "`unknownLesson`", body = TSandboxResponse),
+        (status = 422, description = "`compileError`", body = TSandboxResponse),
+        (status = 429, description = "`rateLimited` or `quotaExceeded`, with `Retry-After`", body = TSandboxResponse),
+        (status = 500, description = "`internalError`", body = TSandboxResponse),
+        (status = 501, description = "`notSupported`: the runner is a coordinator, which has no sandbox", body = TSandboxResponse),
+        (status = 503, description = "`runnerBusy` or `runnerRestarting`", body = TSandboxResponse),
+        (status = 504, description = "`timeout`", body = TSandboxResponse),
+    ),
+)]
+async fn sandbox(
+    Extension(principal): Extension<auth::Principal>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<TSandboxRequest>,
+) -> Response {
+    if let Err(err) = principal.authorize_course(&request.submission.course_slug) {
+        tracing::warn!("Rejected sandbox: {err}");
+        return auth_error_response(err);
+    }
+    let request_id = correlation_header(&headers, &REQUEST_ID_HEADER)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let session_id = principal.session_id
+        .or_else(|| correlation_header(&headers, &SESSION_ID_HEADER));
+    let span = tracing::info_span!("sandbox",
+        request_id = %request_id,
+        session_id = %session_id.as_deref().unwrap_or("-"),
+        course = %request.submission.course_slug,
+        lesson = %request.submission.lesson_slug,
+    );
+    // the sandbox builds like a submission, so it counts toward the same limits
+    let ip = client_ip(&headers, peer);
+    let limited = limited_session(session_id.as_deref(), ip);
+    if let Err(exceeded) = LIMITS.admit(Some(&limited), Some(ip)) {
+        span.in_scope(|| tracing::warn!("Rejected sandbox: {}", exceeded.message));
+        metrics::RATE_LIMITED.with_label_values(&[exceeded.limit]).inc();
+        let response = TSandboxResponse::error(exceeded.code, exceeded.message).with_retry_after(exceeded.retry_after);
+        return json_response(request_id, response.status(), response.retry_after(), response);
+    }
+    let (response, timings) = sandbox_raw(request).instrument(span).await;
+    LIMITS.charge(Some(&limited), timings.iter().map(|(_, duration)| *duration).sum());
+    let outcome = if response.status().is_success() { "ok" } else { "error" };
+    metrics::SANDBOX_REQUESTS.with_label_values(&[outcome]).inc();
+    json_response(request_id, response.status(), response.retry_after(), response)
+}
+
+async fn sandbox_raw(request: TSandboxRequest) -> (TSandboxResponse, Timings) {
+    tracing::debug!("sandbox: {:?}", request);
+    if shutdown::is_draining() {
+        return (sandbox_error(runner_restarting_error()), Vec::new());
+    }
+    if COORDINATOR.is_some() {
+        return (TSandboxResponse::error(ErrorCode::NotSupported, "The sandbox is not available on a coordinator; send it to a standalone runner"), Vec::new());
+    }
+    let lesson_slug = &request.submission.lesson_slug;
+    let Some(lesson) = COURSE.lesson(lesson_slug) else {
+        tracing::error!("lesson not found: {lesson_slug}");
+        return (TSandboxResponse::error(ErrorCode::UnknownLesson, format!("Lesson not found: {lesson_slug}")), Vec::new());
+    };
+    let toolchain = match TOOLCHAINS.select(&request.submission, lesson) {
+        Ok(toolchain) => toolchain,
+        Err(reason) => {
+            tracing::warn!("Rejected request: {reason}");
+            return (TSandboxResponse::error(ErrorCode::InvalidSubmission, reason), Vec::new());
+        }
+    };
+    if let Err(reason) = request.submission.validate() {
+        tracing::warn!("Invalid submission: {reason}");
+        return (TSandboxResponse::error(ErrorCode::InvalidSubmission, reason), Vec::new());
+    }
+    let (submission, scenarios) = match sandbox::scenarios(request, lesson) {
+        Ok(scenarios) => scenarios,
+        Err(err) => {
+            tracing::warn!("Invalid transactions: {err:#}");
+            return (TSandboxResponse::error(ErrorCode::InvalidSubmission, format!("{err:#}")), Vec::new());
+        }
+    };
+
+    tracing::info!("Invoking the program of lesson {lesson:?}");
+    let (outcome, timings) = run_job(toolchain, submission, move |executor| {
+        Box::pin(async move { executor.invoke(lesson, &scenarios).await })
+    }).await;
+    let response = match outcome {
+        Ok(outcome) => TSandboxResponse::from(outcome),
+        Err(err) => sandbox_error(err),
+    };
+    (response, timings)
+}
+
+fn sandbox_error(err: executor::SolveError) -> TSandboxResponse {
+    TSandboxResponse::error(err.code, err.message).with_diagnostics(err.diagnostics)
+}
+
+/// Durations of the grading phases, by phase name
+type Timings = Vec<(String, std::time::Duration)>;
+
+async fn solve_measured(test_request: TTestRequest) -> (TTestResponse, Timings) {
     let start = std::time::Instant::now();
-    let response = solve_raw(Json(test_request)).await;
+    // unknown slugs are not used as labels, to keep the number of series bounded
+    let lesson_label = match COURSE.lesson(&test_request.lesson_slug) {
+        Some(lesson) => lesson.slug.clone(),
+        None => "unknown".to_string(),
+    };
+    let (response, timings) = solve_raw(test_request).await;
     let elapsed = start.elapsed();
     tracing::info!("Serving 'solve' took {:?} seconds", elapsed);
-    response
+    let outcome = match response.error_code() {
+        None => "passed",
+        Some(ErrorCode::TestFailure) => "failed",
+        Some(_) => "error",
+    };
+    metrics::SOLVE_REQUESTS.with_label_values(&[&lesson_label, outcome]).inc();
+    (response, timings)
 }
 
-async fn solve_raw(Json(test_request): Json<TTestRequest>) -> Json<TTestResponse> {
+async fn solve_raw(test_request: TTestRequest) -> (TTestResponse, Timings) {
     tracing::debug!("solve: {:?}", test_request);
-    let session_id = "session_0001"; //TODO: get session_id from headers
-    //TODO: add session locking
-
+    if shutdown::is_draining() {
+        return (runner_restarting(), Vec::new());
+    }
     let lesson_slug = &test_request.lesson_slug;
-    tracing::debug!("session: {session_id} lesson_slug: {lesson_slug}");
-    let Some(lesson) = COURSE.lesson(&lesson_slug) else {
+    let Some(lesson) = COURSE.lesson(lesson_slug) else {
         tracing::error!("lesson not found: {lesson_slug}");
-        return Json(TTestResponse::error(format!("Lesson not found: {lesson_slug}")));
-    };
-
-    let dir = std::path::PathBuf::from("/tmp")
-        .join(session_id);
-    tracing::info!("Solving lesson {lesson:?} in {dir:?}");
-    let executor = executor::TestExecutor::new(dir, test_request);
-    let response = match executor.perform_test(lesson).await {
-        Ok(mut test_results) => {
-            test_results.sort_by(|a, b| a.title.cmp(&b.title));
-            tracing::info!("Results: {test_results:?}");
-            TTestResponse::from(test_results)
+        return (TTestResponse::error(ErrorCode::UnknownLesson, format!("Lesson not found: {lesson_slug}")), Vec::new());
+    };
+    let toolchain = match TOOLCHAINS.select(&test_request, lesson) {
+        Ok(toolchain) => toolchain,
+        Err(reason) => {
+            tracing::warn!("Rejected request: {reason}");
+            return (TTestResponse::error(ErrorCode::InvalidSubmission, reason), Vec::new());
+        }
+    };
+    tracing::debug!("toolchain: {} lesson_slug: {lesson_slug}", toolchain.name);
+    if let Err(reason) = test_request.validate() {
+        tracing::warn!("Invalid submission: {reason}");
+        return (TTestResponse::error(ErrorCode::InvalidSubmission, reason), Vec::new());
+    }
+
+    // in the coordinator mode the results are cached by the workers, which know their toolchain versions
+    let cache_key = match COORDINATOR.as_ref() {
+        Some(_) => None,
+        None => result_cache_key(lesson, &test_request, toolchain).await,
+    };
+    if let Some(response) = cache_key.as_deref().and_then(|key| RESULT_CACHE.get(key)) {
+        tracing::info!("Serving cached result");
+        return (response, Vec::new());
+    }
+
+    let (response, timings) = match COORDINATOR.as_ref() {
+        // a worker that hangs without missing its heartbeats must not keep the client waiting forever
+        Some(coordinator) => tokio::select! {
+            result = tokio::time::timeout(*JOB_TIMEOUT, coordinator.dispatch(&test_request, &toolchain.name, *MAX_QUEUE as usize)) => match result {
+                Ok(result) => {
+                    let timings = result.timings.into_iter()
+                        .map(|(phase, millis)| (phase, std::time::Duration::from_millis(millis)))
+                        .collect();
+                    (result.response, timings)
+                }
+                Err(_) => {
+                    tracing::warn!("No result from the workers within the job timeout");
+                    let message = format!("Grading did not finish in {} seconds", JOB_TIMEOUT.as_secs());
+                    (TTestResponse::error(ErrorCode::Timeout, message), Vec::new())
+                }
+            },
+            _ = shutdown::cancelled() => (runner_restarting(), Vec::new()),
         },
+        None => grade(lesson, toolchain, test_request).await,
+    };
+    if let Some(cache_key) = cache_key {
+        RESULT_CACHE.put(cache_key, &response);
+    }
+    (response, timings)
+}
+
+/// Grade the submission in this process
+async fn grade(lesson: &'static lesson::Lesson, toolchain: &toolchain::ToolchainProfile, test_request: TTestRequest) -> (TTestResponse, Timings) {
+    tracing::info!("Solving lesson {lesson:?}");
+    let warnings = match lesson.meta.lints {
+        lesson::Lints::Off => vec![],
+        _ => analysis::analyze(&test_request.files, lesson.meta.kind),
+    };
+    let (test_run, timings) = run_job(toolchain, test_request, |executor| Box::pin(executor.perform_test(lesson))).await;
+    let response = match test_run {
+        Ok(mut test_run) => {
+            test_run.tests.sort_by(|a, b| a.title.cmp(&b.title));
+            tracing::info!("Results ({:?}): {:?}", test_run.mode, test_run.tests);
+            if lesson.meta.lints == lesson::Lints::Deny && !warnings.is_empty() {
+                test_run.tests.push(TTest::error(analysis::TEST_TITLE, format!("{} warning(s) of the static analysis; see the diagnostics", warnings.len())));
+            }
+            TTestResponse::with_hidden(test_run.tests, &lesson.meta.hidden_tests)
+                .with_execution_mode(test_run.mode)
+                .with_diagnostics(warnings)
+        },
+        Err(err) => TTestResponse::error(err.code, &err.message).with_diagnostics(err.diagnostics),
+    };
+    let response = match toolchain.describe().await {
+        Ok(described) => response.with_toolchain(described),
+        Err(err) => {
+            tracing::warn!("Cannot describe toolchain {}: {err:#}", toolchain.name);
+            response
+        }
+    };
+    (response, timings)
+}
+
+/// Work of a job on its executor, e.g. `TestExecutor::perform_test`
+type Work<'e, T> = std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<T>> + Send + 'e>>;
+
+/// Do the work in a job slot of this process, within the job timeout; failures are reported as a `SolveError`
+async fn run_job<T>(
+    toolchain: &toolchain::ToolchainProfile,
+    test_request: TTestRequest,
+    work: impl for<'e> FnOnce(&'e executor::TestExecutor) -> Work<'e, T>,
+) -> (Result<T, executor::SolveError>, Timings) {
+    if JOB_SLOTS.available() == 0 && metrics::QUEUE_DEPTH.get() >= *MAX_QUEUE {
+        tracing::warn!("Queue is full");
+        return (Err(executor::SolveError::new(ErrorCode::RunnerBusy, "Runner is busy, try again later")), Vec::new());
+    }
+    let queued = metrics::track(&metrics::QUEUE_DEPTH);
+    let slot = JOB_SLOTS.acquire().await;
+    drop(queued);
+    if shutdown::is_draining() {
+        return (Err(runner_restarting_error()), Vec::new());
+    }
+    let _active = metrics::track(&metrics::ACTIVE_JOBS);
+
+    // the submissions taking this slot share its working directory, to reuse its warm `target`
+    let dir = slot.work_dir(toolchain);
+    tracing::info!("Running job in {dir:?}");
+    let backend = match toolchain.backend() {
+        Ok(backend) => backend,
         Err(err) => {
-            tracing::error!("{}", err);
-            TTestResponse::error(err.to_string())
+            tracing::error!("{err:#}");
+            return (Err(executor::SolveError::new(ErrorCode::InternalError, format!("{err:#}"))), Vec::new());
+        }
+    };
+    let executor = executor::TestExecutor::new(dir, test_request, backend);
+    let timings = || executor.timings().into_iter()
+        .map(|(phase, duration)| (phase.to_string(), duration))
+        .collect();
+    let result = tokio::select! {
+        result = tokio::time::timeout(*JOB_TIMEOUT, work(&executor)) => match result {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::error!("Timed out after {:?}", *JOB_TIMEOUT);
+                let err = executor::SolveError::new(ErrorCode::Timeout, format!("Grading did not finish in {} seconds", JOB_TIMEOUT.as_secs()));
+                return (Err(err), timings());
+            }
         },
+        _ = shutdown::cancelled() => {
+            tracing::warn!("Cancelled by shutdown");
+            return (Err(runner_restarting_error()), timings());
+        }
     };
-    Json(response)
+    let result = result.map_err(|err| {
+        tracing::error!("{}", err);
+        match err.downcast::<executor::SolveError>() {
+            Ok(err) => err,
+            Err(err) => executor::SolveError::new(ErrorCode::InternalError, err.to_string()),
+        }
+    });
+    (result, timings())
+}
+
+fn runner_restarting() -> TTestResponse {
+    let err = runner_restarting_error();
+    TTestResponse::error(err.code, err.message)
+}
+
+fn runner_restarting_error() -> executor::SolveError {
+    executor::SolveError::new(ErrorCode::RunnerRestarting, "Runner is restarting, try again in a moment")
+}
+
+/// Key for `RESULT_CACHE`, or `None` when the versions it depends on cannot be determined
+async fn result_cache_key(lesson: &lesson::Lesson, test_request: &TTestRequest, toolchain: &toolchain::ToolchainProfile) -> Option<String> {
+    let fingerprint = lesson.fingerprint()
+        .map_err(|err| tracing::warn!("Cannot fingerprint lesson {}: {err}", lesson.slug))
+        .ok()?;
+    let toolchain_version = toolchain.version().await
+        .map_err(|err| tracing::warn!("Cannot determine version of toolchain {}: {err}", toolchain.name))
+        .ok()?;
+    Some(cache::cache_key(&lesson.slug, &fingerprint, &test_request.files, &format!("{}: {}", toolchain.name, toolchain_version)))
 }
 
@@ -0,0 +1,142 @@
+//! Prometheus metrics, served at `/metrics`
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+lazy_static::lazy_static!(
+    static ref REGISTRY: Registry = Registry::new_custom(Some("agora".to_string()), None).unwrap();
+
+    /// Solve requests by lesson slug and outcome (`passed`, `failed` or `error`)
+    pub static ref SOLVE_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("solve_requests_total", "Solve requests by lesson and outcome"),
+        &["lesson", "outcome"],
+    ).unwrap());
+
+    /// Sandbox requests by outcome (`ok` or `error`)
+    pub static ref SANDBOX_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("sandbox_requests_total", "Sandbox requests by outcome"),
+        &["outcome"],
+    ).unwrap());
+
+    /// Duration of the grading phases: `template`, `warmup`, `build` and `test`
+    pub static ref PHASE_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("solve_phase_seconds", "Duration of the grading phases")
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0, 160.0]),
+        &["phase"],
+    ).unwrap());
+
+    /// Requests waiting for a free job slot
+    pub static ref QUEUE_DEPTH: IntGauge = register(IntGauge::new(
+        "queue_depth", "Solve requests waiting for a free job slot",
+    ).unwrap());
+
+    /// Jobs currently being graded
+    pub static ref ACTIVE_JOBS: IntGauge = register(IntGauge::new(
+        "active_jobs", "Solve requests currently being graded",
+    ).unwrap());
+
+    /// Builds of submitted code
+    pub static ref BUILDS: IntCounter = register(IntCounter::new(
+        "builds_total", "Builds of submitted code",
+    ).unwrap());
+
+    /// Builds of submitted code that failed; together with `builds_total`, gives the compile failure rate
+    pub static ref BUILD_FAILURES: IntCounter = register(IntCounter::new(
+        "build_failures_total", "Builds of submitted code that failed",
+    ).unwrap());
+
+    /// Lookups in the runner caches by cache name and result (`hit` or `miss`)
+    pub static ref CACHE_LOOKUPS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("cache_lookups_total", "Cache lookups by cache and result"),
+        &["cache", "result"],
+    ).unwrap());
+
+    /// Submissions turned away by a rate limit or quota, by limit name
+    pub static ref RATE_LIMITED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rate_limited_total", "Submissions rejected by rate limits and quotas"),
+        &["limit"],
+    ).unwrap());
+
+    /// Subprocesses that were killed, either by a signal or because the job was dropped before they finished
+    pub static ref SUBPROCESS_KILLS: IntCounter = register(IntCounter::new(
+        "subprocess_kills_total", "Subprocesses killed before finishing on their own",
+    ).unwrap());
+);
+
+/// Register all metrics up front, so that they are exported before their first use
+pub fn init() {
+    lazy_static::initialize(&SOLVE_REQUESTS);
+    lazy_static::initialize(&SANDBOX_REQUESTS);
+    lazy_static::initialize(&PHASE_SECONDS);
+    lazy_static::initialize(&QUEUE_DEPTH);
+    lazy_static::initialize(&ACTIVE_JOBS);
+    lazy_static::initialize(&BUILDS);
+    lazy_static::initialize(&BUILD_FAILURES);
+    lazy_static::initialize(&CACHE_LOOKUPS);
+    lazy_static::initialize(&RATE_LIMITED);
+    lazy_static::initialize(&SUBPROCESS_KILLS);
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+/// Keeps the gauge incremented for as long as the returned guard lives
+pub fn track(gauge: &'static IntGauge) -> GaugeGuard {
+    gauge.inc();
+    GaugeGuard(gauge)
+}
+
+pub struct GaugeGuard(&'static IntGauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub fn cache_lookup(cache: &str, hit: bool) {
+    CACHE_LOOKUPS.with_label_values(&[cache, if hit { "hit" } else { "miss" }]).inc();
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_registered_metrics() {
+        SOLVE_REQUESTS.with_label_values(&["sysvar", "passed"]).inc();
+        // a label of its own, as the executor tests look up the warm-up cache concurrently
+        cache_lookup("render", true);
+        let rendered = render().unwrap();
+        assert!(rendered.contains(r#"agora_solve_requests_total{lesson="sysvar",outcome="passed"} 1"#), "{rendered}");
+        assert!(rendered.contains(r#"agora_cache_lookups_total{cache="render",result="hit"} 1"#), "{rendered}");
+    }
+
+    #[tokio::test]
+    async fn queue_depth_of_jobs_waiting_for_a_slot() {
+        static SLOTS: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(1);
+        // a gauge of its own, as `QUEUE_DEPTH` is shared with the other tests
+        let queue_depth: &'static IntGauge = Box::leak(Box::new(IntGauge::new("test_queue_depth", "test").unwrap()));
+        let running = SLOTS.acquire().await.unwrap();
+        let waiting = tokio::spawn(async move {
+            let _queued = track(queue_depth);
+            drop(SLOTS.acquire().await.unwrap());
+        });
+        // let the waiting job queue up behind the running one
+        tokio::task::yield_now().await;
+        assert_eq!(queue_depth.get(), 1);
+        drop(running);
+        waiting.await.unwrap();
+        assert_eq!(queue_depth.get(), 0);
+    }
+}
@@ -0,0 +1,54 @@
+//! The sandbox: ad-hoc transactions against the submitted program, run by the scenario host like a lesson scenario,
+//! but reported as they happened instead of being graded
+
+use crate::lesson::Lesson;
+use crate::scenario::Scenarios;
+use crate::types::{TSandboxRequest, TTestRequest};
+
+/// Address of the program when neither the request, the submitted code nor the lesson tell one
+const DEFAULT_PROGRAM_ID: &str = "Sandbox111111111111111111111111111111111111";
+
+/// The submission to build, and its transactions as a scenario
+pub fn scenarios(request: TSandboxRequest, lesson: &Lesson) -> anyhow::Result<(TTestRequest, Scenarios)> {
+    // Anchor programs check that they run at their declared address
+    let program_id = request.program_id
+        .or_else(|| request.submission.files.iter()
+            .find_map(|file| DECLARE_ID_REGEX.captures(&file.content))
+            .map(|captures| captures["id"].to_string()))
+        .or_else(|| lesson.scenarios.as_ref().map(|scenarios| scenarios.program_id.clone()))
+        .unwrap_or_else(|| DEFAULT_PROGRAM_ID.to_string());
+    let scenarios = Scenarios::sandbox(program_id, request.accounts, request.transactions)?;
+    Ok((request.submission, scenarios))
+}
+
+lazy_static::lazy_static!(
+    static ref DECLARE_ID_REGEX: regex::Regex = regex::Regex::new(r#"declare_id!\s*\(\s*"(?P<id>\w+)"\s*\)"#).unwrap();
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content: &str, program_id: Option<&str>) -> TSandboxRequest {
+        serde_json::from_value(serde_json::json!({
+            "runner": "solana",
+            "courseSlug": "intro-to-solana",
+            "lessonSlug": "anchor-counter",
+            "files": [{ "path": "lib.rs", "content": content }],
+            "programId": program_id,
+            "transactions": [{ "instructions": [{ "accounts": [{ "account": "payer", "signer": true, "writable": true }] }] }],
+        })).unwrap()
+    }
+
+    #[test]
+    fn program_id() {
+        let lesson = crate::COURSE.lesson("anchor-counter").unwrap();
+        let (_, scenarios) = super::scenarios(request(r#"declare_id!("Counter111111111111111111111111111111111111");"#, None), lesson).unwrap();
+        assert_eq!(scenarios.program_id, "Counter111111111111111111111111111111111111");
+        let (_, scenarios) = super::scenarios(request("", Some("11111111111111111111111111111111")), lesson).unwrap();
+        assert_eq!(scenarios.program_id, "11111111111111111111111111111111");
+        let (_, scenarios) = super::scenarios(request("", None), lesson).unwrap();
+        assert_eq!(scenarios.program_id, DEFAULT_PROGRAM_ID);
+        assert!(super::scenarios(request("", Some("not-an-address")), lesson).is_err());
+    }
+}
@@ -0,0 +1,455 @@
+//! Declarative lesson scenarios: the accounts, the transactions and the expected outcome as data, in `scenarios.json`
+//!
+//! A lesson with scenarios needs no `lesson_tests`. The runner does not link a Solana runtime (see `TestMode::Prebuilt`),
+//! so the scenarios are executed by the scenario host of the toolchain (see `scenario-host/`), which reports what
+//! happened; the runner compares that with the expectations, and makes a `TTest` of every scenario.
+//!
+//! Accounts are referred to by name: the ones declared by the scenario, `program` (the lesson program),
+//! `payer` (pays for and signs every transaction) and `system` (the system program); or by a base58 address.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::account_diff;
+use crate::types::{TAccountDiff, TTest};
+
+/// Names that need not be declared by a scenario
+const BUILTIN_NAMES: [&str; 3] = ["program", "payer", "system"];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Scenarios {
+    /// Address of the lesson program
+    pub program_id: String,
+    pub scenarios: Vec<Scenario>,
+}
+
+/// One test: accounts are created, the transactions are sent one by one, and the accounts are checked at the end
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Scenario {
+    /// Title of the test
+    pub name: String,
+    /// Accounts that exist before the first transaction, by name
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountSpec>,
+    pub transactions: Vec<TransactionSpec>,
+    /// State of accounts after the last transaction, by name
+    #[serde(default)]
+    pub expect: BTreeMap<String, ExpectedAccount>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AccountSpec {
+    /// Base58 address; a new one when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    /// An account without lamports and data is not created, it only gets an address
+    #[serde(default)]
+    pub lamports: u64,
+    /// The system program when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = String)]
+    pub data: Hex,
+    /// Size of zeroed data, instead of `data`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub space: Option<usize>,
+    #[serde(default)]
+    pub executable: bool,
+    /// The host holds the keypair of a new address, so that the account can sign
+    #[serde(default)]
+    pub signer: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TransactionSpec {
+    pub instructions: Vec<InstructionSpec>,
+    /// Part of the error the transaction must fail with; it must succeed when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect_error: Option<String>,
+    /// Log lines the transaction must print, in this order; other lines may come in between
+    #[serde(default)]
+    pub expect_logs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct InstructionSpec {
+    /// The lesson program when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<String>,
+    #[serde(default)]
+    pub accounts: Vec<AccountMetaSpec>,
+    #[serde(default)]
+    #[schema(value_type = String)]
+    pub data: Hex,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AccountMetaSpec {
+    pub account: String,
+    #[serde(default)]
+    pub signer: bool,
+    #[serde(default)]
+    pub writable: bool,
+}
+
+/// Expected state of an account; only the given fields are checked
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ExpectedAccount {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamports: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Hex>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_len: Option<usize>,
+    /// The account must not exist anymore
+    #[serde(default)]
+    pub closed: bool,
+}
+
+/// Bytes, written as a hex string
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hex(pub Vec<u8>);
+
+impl Serialize for Hex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex::decode(hex.trim_start_matches("0x")).map(Hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the scenario host reports about a scenario
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Outcome {
+    pub name: String,
+    /// The scenario could not be set up, e.g. the program did not load
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Base58 addresses of the named accounts, including the built-in ones
+    #[serde(default)]
+    pub addresses: BTreeMap<String, String>,
+    #[serde(default)]
+    pub transactions: Vec<TransactionOutcome>,
+    /// State of the declared accounts and the payer after the last transaction; `None` when the account does not exist
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Option<AccountState>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionOutcome {
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub logs: Vec<String>,
+    #[serde(default)]
+    pub compute_units: Option<u64>,
+    /// Accounts of the instructions and the payer, by name (or address, when unnamed)
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountChange>,
+}
+
+/// State of an account before and after a transaction; `None` when the account does not exist
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountChange {
+    #[serde(default)]
+    pub before: Option<AccountState>,
+    #[serde(default)]
+    pub after: Option<AccountState>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountState {
+    pub lamports: u64,
+    pub owner: String,
+    #[schema(value_type = String)]
+    pub data: Hex,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+/// Input of the scenario host
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Job<'a> {
+    /// Name of the `.so` in `SBF_OUT_DIR`
+    program_name: &'a str,
+    #[serde(flatten)]
+    scenarios: &'a Scenarios,
+}
+
+impl Scenarios {
+    pub const FILE_NAME: &'static str = "scenarios.json";
+
+    /// Scenarios of the lesson in `dir`, if it has any
+    pub fn from_dir(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(Self::FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        let scenarios: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid scenarios in {}", path.display()))?;
+        scenarios.validate()
+            .with_context(|| format!("Invalid scenarios in {}", path.display()))?;
+        Ok(Some(scenarios))
+    }
+
+    /// A single scenario of ad-hoc transactions without expectations, as run by the sandbox
+    pub fn sandbox(program_id: String, accounts: BTreeMap<String, AccountSpec>, transactions: Vec<TransactionSpec>) -> anyhow::Result<Self> {
+        let scenario = Scenario { name: "sandbox".to_string(), accounts, transactions, expect: BTreeMap::new() };
+        let scenarios = Self { program_id, scenarios: vec![scenario] };
+        scenarios.validate()?;
+        Ok(scenarios)
+    }
+
+    /// Check that every name refers to an account, so that mistakes surface when the lessons are loaded
+    fn validate(&self) -> anyhow::Result<()> {
+        if !is_address(&self.program_id) {
+            anyhow::bail!("programId is not a base58 address: {:?}", self.program_id);
+        }
+        for scenario in &self.scenarios {
+            let known = |name: &str| scenario.accounts.contains_key(name) || BUILTIN_NAMES.contains(&name) || is_address(name);
+            let mut names = Vec::new();
+            for account in scenario.accounts.values() {
+                names.extend(account.owner.as_deref());
+                if account.space.is_some() && !account.data.0.is_empty() {
+                    anyhow::bail!("Scenario {:?}: an account has both data and space", scenario.name);
+                }
+            }
+            for instruction in scenario.transactions.iter().flat_map(|transaction| &transaction.instructions) {
+                names.extend(instruction.program.as_deref());
+                names.extend(instruction.accounts.iter().map(|meta| meta.account.as_str()));
+            }
+            for (name, expected) in &scenario.expect {
+                names.push(name);
+                names.extend(expected.owner.as_deref());
+            }
+            if let Some(unknown) = names.into_iter().find(|name| !known(name)) {
+                anyhow::bail!("Scenario {:?}: unknown account {unknown:?}", scenario.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Input of the scenario host, for the compiled program `<program_name>.so`
+    pub fn job(&self, program_name: &str) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&Job { program_name, scenarios: self })?)
+    }
+
+    /// Compare the outcomes with the expectations; a scenario without an outcome fails
+    pub fn check(&self, outcomes: &[Outcome]) -> Vec<TTest> {
+        self.scenarios.iter()
+            .map(|scenario| {
+                let Some(outcome) = outcomes.iter().find(|outcome| outcome.name == scenario.name) else {
+                    return TTest::error(&scenario.name, "Scenario was not run");
+                };
+                let mismatches = scenario.mismatches(outcome);
+                let test = if mismatches.is_empty() {
+                    TTest::ok(&scenario.name)
+                } else {
+                    TTest::error(&scenario.name, mismatches.join("\n"))
+                };
+                test.with_accounts(scenario.account_diffs(outcome))
+            })
+            .collect()
+    }
+}
+
+impl Scenario {
+    /// How the outcome differs from the expectations, one sentence each
+    fn mismatches(&self, outcome: &Outcome) -> Vec<String> {
+        if let Some(error) = &outcome.error {
+            return vec![error.clone()];
+        }
+        let mut mismatches = Vec::new();
+        for (index, expected) in self.transactions.iter().enumerate() {
+            let number = index + 1;
+            let Some(actual) = outcome.transactions.get(index) else {
+                mismatches.push(format!("Transaction {number} was not sent"));
+                continue;
+            };
+            match (&expected.expect_error, &actual.error) {
+                (None, Some(error)) => mismatches.push(format!("Transaction {number} failed: {error}")),
+                (Some(expected), None) => mismatches.push(format!("Transaction {number} succeeded, expected it to fail with {expected:?}")),
+                (Some(expected), Some(error)) if !error.contains(expected.as_str()) => {
+                    mismatches.push(format!("Transaction {number} failed with {error:?}, expected {expected:?}"));
+                }
+                _ => {}
+            }
+            let mut logs = actual.logs.iter();
+            for line in &expected.expect_logs {
+                if !logs.any(|logged| logged.contains(line.as_str())) {
+                    mismatches.push(format!("Transaction {number} did not log {line:?}"));
+                    break;
+                }
+            }
+        }
+        for (name, expected) in &self.expect {
+            let actual = outcome.accounts.get(name).cloned().flatten();
+            mismatches.extend(expected.mismatches(name, actual.as_ref(), &outcome.addresses));
+        }
+        mismatches
+    }
+}
+
+impl Scenario {
+    /// How the accounts changed from before their first transaction to after their last one, including the
+    /// accounts with expectations
+    fn account_diffs(&self, outcome: &Outcome) -> Vec<TAccountDiff> {
+        let mut states: BTreeMap<&str, (Option<&AccountState>, Option<&AccountState>)> = BTreeMap::new();
+        for transaction in &outcome.transactions {
+            for (name, change) in &transaction.accounts {
+                states.entry(name.as_str()).or_insert((change.before.as_ref(), None)).1 = change.after.as_ref();
+            }
+        }
+        for name in self.expect.keys() {
+            let state = outcome.accounts.get(name).and_then(Option::as_ref);
+            states.entry(name.as_str()).or_insert((state, state));
+        }
+        states.into_iter()
+            .filter_map(|(name, (before, after))| {
+                let address = outcome.addresses.get(name).map_or(name, String::as_str);
+                account_diff::diff(name, address, before, after, self.expect.get(name), &outcome.addresses)
+            })
+            .collect()
+    }
+}
+
+impl ExpectedAccount {
+    fn mismatches(&self, name: &str, actual: Option<&AccountState>, addresses: &BTreeMap<String, String>) -> Vec<String> {
+        let actual = match (actual, self.closed) {
+            (Some(actual), true) => return vec![format!("{name}: expected to be closed, has {} lamports", actual.lamports)],
+            (None, true) => return vec![],
+            (None, false) => return vec![format!("{name}: does not exist")],
+            (Some(actual), false) => actual,
+        };
+        let mut mismatches = Vec::new();
+        if let Some(lamports) = self.lamports.filter(|lamports| *lamports != actual.lamports) {
+            mismatches.push(format!("{name}: {} lamports, expected {lamports}", actual.lamports));
+        }
+        if let Some(owner) = &self.owner {
+            let expected_address = addresses.get(owner).unwrap_or(owner);
+            if *expected_address != actual.owner {
+                let actual_owner = addresses.iter()
+                    .find(|(_, address)| **address == actual.owner)
+                    .map_or(actual.owner.as_str(), |(name, _)| name.as_str());
+                mismatches.push(format!("{name}: owned by {actual_owner}, expected {owner}"));
+            }
+        }
+        if let Some(data_len) = self.data_len.filter(|data_len| *data_len != actual.data.0.len()) {
+            mismatches.push(format!("{name}: {} bytes of data, expected {data_len}", actual.data.0.len()));
+        }
+        if let Some(Hex(data)) = &self.data {
+            let actual = &actual.data.0;
+            if let Some(offset) = data.iter().zip(actual).position(|(expected, actual)| expected != actual) {
+                mismatches.push(format!("{name}: data byte {offset} is {:#04x}, expected {:#04x}", actual[offset], data[offset]));
+            } else if data.len() != actual.len() {
+                mismatches.push(format!("{name}: {} bytes of data, expected {}", actual.len(), data.len()));
+            }
+        }
+        mismatches
+    }
+}
+
+/// Whether the text is a base58 encoded 32 byte address
+fn is_address(text: &str) -> bool {
+    bs58::decode(text).into_vec().is_ok_and(|bytes| bytes.len() == 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenarios() -> Scenarios {
+        serde_json::from_value(serde_json::json!({
+            "programId": "TransferLamports111111111111111111111111111",
+            "scenarios": [{
+                "name": "test_lamport_balances",
+                "accounts": {
+                    "source": { "lamports": 5, "owner": "program" },
+                    "destination": { "lamports": 890875 },
+                },
+                "transactions": [{
+                    "instructions": [{ "accounts": [{ "account": "source", "writable": true }, { "account": "destination", "writable": true }] }],
+                    "expectLogs": ["success"],
+                }],
+                "expect": {
+                    "source": { "lamports": 0, "owner": "program" },
+                    "destination": { "lamports": 890880, "data": "0x" },
+                },
+            }],
+        })).unwrap()
+    }
+
+    #[test]
+    fn validate_names() {
+        assert!(scenarios().validate().is_ok());
+        let mut scenarios = scenarios();
+        scenarios.scenarios[0].expect.insert("recipient".to_string(), ExpectedAccount::default());
+        assert_eq!(scenarios.validate().unwrap_err().to_string(), r#"Scenario "test_lamport_balances": unknown account "recipient""#);
+    }
+
+    #[test]
+    fn report_mismatches() {
+        let outcome: Outcome = serde_json::from_value(serde_json::json!({
+            "name": "test_lamport_balances",
+            "addresses": {
+                "program": "TransferLamports111111111111111111111111111",
+                "system": "11111111111111111111111111111111",
+            },
+            "transactions": [{ "logs": ["Program log: success"], "computeUnits": 150 }],
+            "accounts": {
+                "source": { "lamports": 5, "owner": "11111111111111111111111111111111", "data": "" },
+                "destination": { "lamports": 890875, "owner": "11111111111111111111111111111111", "data": "00" },
+            },
+        })).unwrap();
+        let tests = serde_json::to_value(scenarios().check(std::slice::from_ref(&outcome))).unwrap();
+        assert_eq!(tests[0]["passed"], false);
+        assert_eq!(tests[0]["error"], "destination: 890875 lamports, expected 890880\n\
+                                       destination: 1 bytes of data, expected 0\n\
+                                       source: 5 lamports, expected 0\n\
+                                       source: owned by system, expected program");
+        assert_eq!(tests[0]["accounts"][1], serde_json::json!({
+            "name": "source",
+            "address": "source",
+            "changes": [
+                { "field": "lamports", "before": "5", "after": "5", "expected": "0" },
+                { "field": "owner", "before": "system", "after": "system", "expected": "program" },
+            ],
+        }));
+
+        let failed = Outcome {
+            transactions: vec![TransactionOutcome { error: Some("Error processing Instruction 0: custom program error: 0x1".to_string()), ..Default::default() }],
+            ..outcome
+        };
+        let tests = scenarios().check(&[failed]);
+        let error = serde_json::to_value(&tests).unwrap()[0]["error"].as_str().unwrap().to_string();
+        assert!(error.starts_with("Transaction 1 failed: Error processing Instruction 0: custom program error: 0x1\nTransaction 1 did not log \"success\""), "{error}");
+
+        let tests = scenarios().check(&[]);
+        assert_eq!(serde_json::to_value(&tests).unwrap()[0]["error"], "Scenario was not run");
+    }
+}
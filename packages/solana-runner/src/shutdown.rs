@@ -0,0 +1,46 @@
+//! Graceful shutdown: on SIGTERM the runner stops taking new jobs and reports itself not ready, lets the running
+//! jobs finish within a grace period, and then cancels the rest
+
+lazy_static::lazy_static!(
+    /// Becomes `true` when the runner stops taking new jobs
+    static ref DRAINING: tokio::sync::watch::Sender<bool> = tokio::sync::watch::channel(false).0;
+    /// Becomes `true` when the remaining jobs are to be cancelled
+    static ref CANCEL: tokio::sync::watch::Sender<bool> = tokio::sync::watch::channel(false).0;
+);
+
+/// Resolves on SIGTERM, or on Ctrl-C when running in a terminal
+pub async fn signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("SIGTERM handler can be installed");
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C"),
+    }
+}
+
+/// Whether the runner is shutting down, and must not start new jobs
+pub fn is_draining() -> bool {
+    *DRAINING.borrow()
+}
+
+pub fn start_draining() {
+    DRAINING.send_replace(true);
+}
+
+/// Resolves when the runner starts shutting down
+pub async fn draining() {
+    let mut draining = DRAINING.subscribe();
+    let _ = draining.wait_for(|draining| *draining).await;
+}
+
+/// Make `cancelled` resolve in all jobs
+pub fn cancel_jobs() {
+    CANCEL.send_replace(true);
+}
+
+/// Resolves when the grace period is over, and the job should give up
+pub async fn cancelled() {
+    let mut cancel = CANCEL.subscribe();
+    // the sender lives in a static, so the channel is never closed
+    let _ = cancel.wait_for(|cancel| *cancel).await;
+}
@@ -0,0 +1,108 @@
+//! Job slots of this process: how many submissions are graded at once, each in a working directory of its own
+//!
+//! A job writes the submitted files under `src/` and builds into `target/`, so jobs running at the same time must not
+//! share a directory. Every slot has its own directory under the working directory of the toolchain, `slot-<n>`, which
+//! stays warm for the jobs that take the slot after it.
+
+use std::path::PathBuf;
+
+use crate::toolchain::ToolchainProfile;
+
+pub struct JobSlots {
+    count: u32,
+    permits: tokio::sync::Semaphore,
+    /// Numbers of the slots no job holds
+    free: std::sync::Mutex<Vec<u32>>,
+}
+
+/// A slot held by a job, until it is dropped
+pub struct JobSlot<'a> {
+    number: u32,
+    slots: &'a JobSlots,
+    _permit: tokio::sync::SemaphorePermit<'a>,
+}
+
+impl JobSlots {
+    pub fn new(count: u32) -> Self {
+        Self {
+            count,
+            permits: tokio::sync::Semaphore::new(count as usize),
+            free: std::sync::Mutex::new((0..count).rev().collect()),
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    /// Wait for a free slot
+    pub async fn acquire(&self) -> JobSlot<'_> {
+        let permit = self.permits.acquire().await.expect("job slots are never closed");
+        let number = self.free.lock().unwrap().pop().expect("a slot is free for every permit");
+        JobSlot { number, slots: self, _permit: permit }
+    }
+
+    /// Resolves once no job holds a slot
+    pub async fn idle(&self) {
+        drop(self.permits.acquire_many(self.count).await);
+    }
+}
+
+impl JobSlot<'_> {
+    /// Working directory of the jobs in this slot
+    pub fn work_dir(&self, toolchain: &ToolchainProfile) -> PathBuf {
+        toolchain.work_dir.join(format!("slot-{}", self.number))
+    }
+}
+
+impl Drop for JobSlot<'_> {
+    fn drop(&mut self) {
+        // before the permit, so that the next job finds the slot free
+        self.slots.free.lock().unwrap().push(self.number);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Replay;
+    use crate::executor::TestExecutor;
+    use crate::types::{TEditorFile, TTestRequest};
+
+    #[tokio::test]
+    async fn concurrent_jobs_have_their_own_files() {
+        let toolchain = ToolchainProfile {
+            work_dir: std::env::temp_dir().join(format!("agora-slots-{}", std::process::id())),
+            ..Default::default()
+        };
+        let slots = JobSlots::new(2);
+        let lesson = crate::COURSE.lesson("02-transfer-lamports").unwrap();
+        // the build takes long enough for the other job to write its files meanwhile
+        let job = |content: &'static str| async {
+            let slot = slots.acquire().await;
+            let dir = slot.work_dir(&toolchain);
+            let test_request = TTestRequest {
+                runner: "solana".to_string(),
+                r#type: None,
+                course_slug: "intro-to-solana".to_string(),
+                lesson_slug: lesson.slug.clone(),
+                files: vec![TEditorFile { path: "lib.rs".to_string(), content: content.to_string() }],
+                image: None,
+            };
+            let backend: Replay = serde_json::from_value(serde_json::json!({
+                "version": "solana-cargo-build-sbf 1.18.26, platform-tools v1.41, rustc 1.75.0",
+                "steps": {
+                    "buildNative": { "files": { "target/deploy/solana_lesson_transfer_lamports.so": "" }, "delayMs": 200 },
+                    "test": { "stdout": "test test_lamport_transfer ... ok\n" },
+                },
+            })).unwrap();
+            let executor = TestExecutor::new(dir.clone(), test_request, Box::new(backend));
+            executor.perform_test(lesson).await.unwrap();
+            std::fs::read_to_string(dir.join("src/lib.rs")).unwrap()
+        };
+        let (first, second) = tokio::join!(job("// first"), job("// second"));
+        assert_eq!((first.as_str(), second.as_str()), ("// first", "// second"));
+        assert_eq!(slots.available(), 2);
+        std::fs::remove_dir_all(&toolchain.work_dir).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+//! Installed Solana toolchains, and routing of requests to them
+//!
+//! Without configuration there is a single profile, using whatever `cargo` and Solana tools are installed.
+//! Several profiles can be described in a JSON file, pointed to by `AGORA_TOOLCHAINS`, e.g. Solana 1.18 and Agave 2.x
+//! side by side; a lesson names the profile it is written for in its `lesson.json`, and the first one serves the rest.
+//! `AGORA_WORK_DIR` moves the working directories of all profiles under one directory, so that several runners
+//! can share a machine; without it, a worker (see `worker.rs`) uses `agora-worker-<pid>` in the temporary directory.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::backend::{Backend, Capabilities, Cargo, Replay};
+use crate::lesson::{Course, Lesson, LessonKind};
+use crate::types::{TTestRequest, TToolchain};
+
+/// Value of `TTestRequest::runner` for requests meant for this runner
+pub const RUNNER_NAME: &str = "solana";
+
+/// An installed Solana toolchain, with its own warm cache
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolchainProfile {
+    pub name: String,
+    /// Values of `TTestRequest::image` served by this profile
+    #[serde(default)]
+    pub images: Vec<String>,
+    /// Directory with `cargo-build-sbf`, `solana` etc. of this toolchain; it is put first on `PATH`
+    #[serde(default)]
+    pub bin_dir: Option<PathBuf>,
+    /// Build output of a program compiled with this toolchain when the image was built, used to warm up the working directory
+    /// of native lessons; it is passed to `rsync`, so it should end with a slash
+    #[serde(default = "default_warm_target")]
+    pub warm_target: PathBuf,
+    /// Like `warm_target`, for Anchor lessons, whose dependencies differ
+    #[serde(default = "default_anchor_warm_target")]
+    pub anchor_warm_target: PathBuf,
+    /// Working directory for the submissions graded with this toolchain; each job slot builds in its `slot-<n>`
+    /// subdirectory, see `slots.rs`
+    #[serde(default = "default_work_dir")]
+    pub work_dir: PathBuf,
+    /// Recorded output of the tools to play back instead of running them, for testing without a Solana toolchain;
+    /// see `backend::Replay`
+    #[serde(default)]
+    pub replay: Option<PathBuf>,
+    /// Executable of `scenario-host/` built against the Solana crates of this toolchain, running the lesson scenarios
+    #[serde(default = "default_scenario_host")]
+    pub scenario_host: PathBuf,
+    /// SBF architecture to build programs for, passed to `cargo build-sbf` and `cargo test-sbf` as `--arch`;
+    /// the default of the tools when missing
+    #[serde(default)]
+    pub arch: Option<String>,
+}
+
+fn default_warm_target() -> PathBuf {
+    PathBuf::from("/tmp/dummy-program/target/")
+}
+
+fn default_anchor_warm_target() -> PathBuf {
+    PathBuf::from("/tmp/dummy-anchor-program/target/")
+}
+
+fn default_scenario_host() -> PathBuf {
+    PathBuf::from("agorapp-solana-scenarios")
+}
+
+fn default_work_dir() -> PathBuf {
+    PathBuf::from("/tmp/session_0001")
+}
+
+impl Default for ToolchainProfile {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            images: vec!["agorapp-solana".to_string()],
+            bin_dir: None,
+            warm_target: default_warm_target(),
+            anchor_warm_target: default_anchor_warm_target(),
+            work_dir: default_work_dir(),
+            replay: None,
+            scenario_host: default_scenario_host(),
+            arch: None,
+        }
+    }
+}
+
+lazy_static::lazy_static!(
+    static ref VERSIONS: tokio::sync::Mutex<std::collections::HashMap<String, String>> = Default::default();
+    static ref CAPABILITIES: tokio::sync::Mutex<std::collections::HashMap<String, Capabilities>> = Default::default();
+);
+
+impl ToolchainProfile {
+    /// Versions of the tools (e.g. `cargo build-sbf --version`), probed once per profile
+    pub async fn version(&self) -> anyhow::Result<String> {
+        let mut versions = VERSIONS.lock().await;
+        if let Some(version) = versions.get(&self.name) {
+            return Ok(version.clone());
+        }
+        let version = self.backend()?.version().await?;
+        versions.insert(self.name.clone(), version.clone());
+        Ok(version)
+    }
+
+    /// Subcommands and arguments the SBF tools support, probed once per profile
+    pub async fn capabilities(&self) -> anyhow::Result<Capabilities> {
+        let mut probed = CAPABILITIES.lock().await;
+        if let Some(capabilities) = probed.get(&self.name) {
+            return Ok(capabilities.clone());
+        }
+        let capabilities = self.backend()?.capabilities().await?;
+        if self.arch.is_some() {
+            capabilities.require("--arch")?;
+        }
+        probed.insert(self.name.clone(), capabilities.clone());
+        Ok(capabilities)
+    }
+
+    /// The toolchain as reported to clients
+    pub async fn describe(&self) -> anyhow::Result<TToolchain> {
+        let capabilities = self.capabilities().await?;
+        Ok(TToolchain { name: self.name.clone(), versions: capabilities.versions })
+    }
+
+    /// The tools grading with this profile
+    pub fn backend(&self) -> anyhow::Result<Box<dyn Backend>> {
+        Ok(match &self.replay {
+            Some(recording) => Box::new(Replay::from_file(recording)?),
+            None => Box::new(Cargo::new(self.clone())),
+        })
+    }
+
+    /// Build output to warm up the working directory of a lesson of this kind with
+    pub fn warm_target(&self, kind: LessonKind) -> &Path {
+        match kind {
+            LessonKind::Native => &self.warm_target,
+            LessonKind::Anchor => &self.anchor_warm_target,
+        }
+    }
+
+    pub fn cargo_cmd(&self) -> Command {
+        self.command("cargo")
+    }
+
+    /// Command for a tool installed by rustup or `cargo install`, falling back to the one on `PATH`
+    pub fn command(&self, name: &str) -> Command {
+        //TODO configurable, or init script?
+        let mut cmd = match dirs::home_dir() {
+            Some(home) => {
+                let exe = home.join(".cargo/bin").join(name);
+                // if executable, use it
+                if exe.is_file() {
+                    Command::new(exe)
+                } else {
+                    Command::new(name)
+                }
+            }
+            None => {
+                Command::new(name)
+            }
+        };
+        if let Some(bin_dir) = &self.bin_dir {
+            let path = std::env::var_os("PATH").unwrap_or_default();
+            let path = std::env::join_paths(std::iter::once(bin_dir.clone()).chain(std::env::split_paths(&path)))
+                .expect("toolchain bin_dir must not contain the PATH separator");
+            cmd.env("PATH", path);
+        }
+        cmd
+    }
+}
+
+#[derive(Debug)]
+pub struct Toolchains {
+    /// The first one is the default
+    pub profiles: Vec<ToolchainProfile>,
+}
+
+/// Directory the working directories of all profiles are moved under: `AGORA_WORK_DIR`, or one of this process for a
+/// worker, as several workers may run on one machine
+fn work_dir_base(configured: Option<std::ffi::OsString>, worker: bool) -> Option<PathBuf> {
+    configured.map(PathBuf::from)
+        .or_else(|| worker.then(|| std::env::temp_dir().join(format!("agora-worker-{}", std::process::id()))))
+}
+
+impl Toolchains {
+    /// Load the profiles from the file in `AGORA_TOOLCHAINS`, or use the default profile
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut toolchains = match std::env::var_os("AGORA_TOOLCHAINS") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None => Self { profiles: vec![ToolchainProfile::default()] },
+        };
+        if let Some(work_dir) = work_dir_base(std::env::var_os("AGORA_WORK_DIR"), *crate::MODE == "worker") {
+            for profile in &mut toolchains.profiles {
+                profile.work_dir = work_dir.join(&profile.name);
+            }
+        }
+        Ok(toolchains)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read toolchains from {}", path.display()))?;
+        let profiles: Vec<ToolchainProfile> = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid toolchains in {}", path.display()))?;
+        if profiles.is_empty() {
+            anyhow::bail!("No toolchains in {}", path.display());
+        }
+        Ok(Self { profiles })
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&ToolchainProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// The profile the lesson is written for; `None` when it is not installed
+    pub fn for_lesson(&self, lesson: &Lesson) -> Option<&ToolchainProfile> {
+        match &lesson.meta.toolchain {
+            Some(name) => self.profile(name),
+            None => Some(&self.profiles[0]),
+        }
+    }
+
+    /// Lessons of the course whose toolchain profile is not installed, with the missing profile
+    pub fn missing(&self, course: &Course) -> Vec<(String, String)> {
+        let mut missing = course.lessons_by_slug.values()
+            .filter(|lesson| self.for_lesson(lesson).is_none())
+            .map(|lesson| (lesson.slug.clone(), lesson.meta.toolchain.clone().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// Choose the profile for a request, rejecting requests meant for a different runner
+    ///
+    /// `runner` is either `solana`, or the name of a profile; `image`, if present, must be served by the chosen profile.
+    /// Without either, the profile of the lesson is chosen; a lesson that declares its profile is only graded with it.
+    pub fn select(&self, test_request: &TTestRequest, lesson: &Lesson) -> Result<&ToolchainProfile, String> {
+        let by_request = self.select_by_request(test_request)?;
+        let by_lesson = self.for_lesson(lesson)
+            .ok_or_else(|| format!("Toolchain {:?} of lesson {:?} is not installed", lesson.meta.toolchain.as_deref().unwrap_or_default(), lesson.slug))?;
+        match by_request {
+            Some(profile) if lesson.meta.toolchain.is_some() && profile.name != by_lesson.name => {
+                Err(format!("Lesson {:?} needs toolchain {:?}, not {:?}", lesson.slug, by_lesson.name, profile.name))
+            }
+            Some(profile) => Ok(profile),
+            None => Ok(by_lesson),
+        }
+    }
+
+    /// The profile chosen by `runner` and `image` of the request, if any
+    fn select_by_request(&self, test_request: &TTestRequest) -> Result<Option<&ToolchainProfile>, String> {
+        let by_runner = if test_request.runner == RUNNER_NAME {
+            None
+        } else {
+            let profile = self.profile(&test_request.runner)
+                .ok_or_else(|| format!("Request is meant for runner {:?}, not {RUNNER_NAME:?}", test_request.runner))?;
+            Some(profile)
+        };
+        let by_image = match &test_request.image {
+            Some(image) => {
+                let profile = self.profiles.iter()
+                    .find(|profile| profile.images.contains(image))
+                    .ok_or_else(|| format!("No toolchain installed for image {image:?}"))?;
+                Some(profile)
+            }
+            None => None,
+        };
+        match (by_runner, by_image) {
+            (Some(by_runner), Some(by_image)) if by_runner.name != by_image.name => {
+                Err(format!("Runner {:?} does not match image {:?}", test_request.runner, test_request.image.as_deref().unwrap_or_default()))
+            }
+            (Some(profile), _) | (None, Some(profile)) => Ok(Some(profile)),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(runner: &str, image: Option<&str>) -> TTestRequest {
+        let body = serde_json::json!({
+            "runner": runner,
+            "image": image,
+            "courseSlug": "intro-to-solana",
+            "lessonSlug": "sysvar",
+            "files": [],
+        });
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn lesson(toolchain: Option<&str>) -> Lesson {
+        let meta = crate::lesson::LessonMeta { toolchain: toolchain.map(str::to_string), ..Default::default() };
+        Lesson { slug: "sysvar".to_string(), dir: PathBuf::from("lessons-code/solana-04-sysvar"), meta, scenarios: None }
+    }
+
+    fn toolchains() -> Toolchains {
+        let profiles: Vec<ToolchainProfile> = serde_json::from_str(r##"[
+            { "name": "solana-1.18", "images": ["agorapp-solana", "agorapp-solana:1.18"] },
+            { "name": "agave-2.1", "images": ["agorapp-solana:2.1"], "binDir": "/opt/agave-2.1/bin" }
+        ]"##).unwrap();
+        Toolchains { profiles }
+    }
+
+    #[test]
+    fn work_dirs_of_workers() {
+        assert_eq!(work_dir_base(Some("/tmp/runner".into()), true), Some(PathBuf::from("/tmp/runner")));
+        assert_eq!(work_dir_base(None, false), None);
+        // the workers on a machine do not share a working directory
+        let worker = work_dir_base(None, true).unwrap();
+        assert!(worker.ends_with(format!("agora-worker-{}", std::process::id())), "{}", worker.display());
+    }
+
+    #[test]
+    fn select_profile() {
+        let toolchains = toolchains();
+        let lesson = lesson(None);
+        let selected = |runner, image| toolchains.select(&request(runner, image), &lesson).map(|profile| profile.name.as_str());
+
+        assert_eq!(selected("solana", None), Ok("solana-1.18"));
+        assert_eq!(selected("solana", Some("agorapp-solana:2.1")), Ok("agave-2.1"));
+        assert_eq!(selected("agave-2.1", None), Ok("agave-2.1"));
+        assert_eq!(selected("agave-2.1", Some("agorapp-solana:2.1")), Ok("agave-2.1"));
+        assert!(selected("agave-2.1", Some("agorapp-solana")).is_err());
+        assert!(selected("docker-runner", Some("rbiosas/nearjs-docker-runner")).is_err());
+        assert!(selected("solana", Some("rbiosas/nearjs-docker-runner")).is_err());
+    }
+
+    #[test]
+    fn select_profile_of_lesson() {
+        let toolchains = toolchains();
+        let agave_lesson = lesson(Some("agave-2.1"));
+        let selected = |runner, image| toolchains.select(&request(runner, image), &agave_lesson).map(|profile| profile.name.as_str());
+
+        assert_eq!(selected("solana", None), Ok("agave-2.1"));
+        assert_eq!(selected("solana", Some("agorapp-solana:2.1")), Ok("agave-2.1"));
+        assert!(selected("solana-1.18", None).is_err());
+        assert!(selected("solana", Some("agorapp-solana")).is_err());
+
+        let missing = lesson(Some("agave-2.2"));
+        assert!(toolchains.select(&request("solana", None), &missing).is_err());
+        assert!(toolchains.for_lesson(&missing).is_none());
+    }
+
+    #[test]
+    fn warm_target_by_lesson_kind() {
+        let toolchains = toolchains();
+        let profile = &toolchains.profiles[0];
+        assert_eq!(profile.warm_target(LessonKind::Native), Path::new("/tmp/dummy-program/target/"));
+        assert_eq!(profile.warm_target(LessonKind::Anchor), Path::new("/tmp/dummy-anchor-program/target/"));
+    }
+}
@@ -1,6 +1,12 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::scenario::{AccountSpec, AccountState, Outcome, TransactionOutcome, TransactionSpec};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[allow(dead_code)] // `type` is part of the protocol, but not used yet
 pub struct TTestRequest {
     pub runner: String,
     pub r#type: Option<String>,
@@ -12,26 +18,224 @@ pub struct TTestRequest {
     pub image: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct TEditorFile {
     pub path: String,
     pub content: String,
 }
 
-#[derive(Serialize)]
+impl TTestRequest {
+    /// Check that the submitted files can be written into the lesson's source directory
+    pub fn validate(&self) -> Result<(), String> {
+        if self.files.is_empty() {
+            return Err("No files submitted".to_string());
+        }
+        for file in &self.files {
+            let path = std::path::Path::new(&file.path);
+            let escapes = path.components()
+                .any(|component| !matches!(component, std::path::Component::Normal(_)));
+            if file.path.is_empty() || escapes {
+                return Err(format!("Invalid file path: {:?}", file.path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why a submission did not pass; stable, so that clients can tell failures apart without matching messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The request carries no valid credentials
+    Unauthorized,
+    /// The credentials do not allow this request, e.g. a token issued for another course
+    Forbidden,
+    UnknownLesson,
+    InvalidSubmission,
+    /// The submitted program does not compile
+    CompileError,
+    /// The program compiles, but the lesson tests do not compile against it
+    TestCompileError,
+    /// Some of the lesson tests failed
+    TestFailure,
+    Timeout,
+    RunnerBusy,
+    /// The runner is shutting down; the submission was not graded, or its grading was cancelled
+    RunnerRestarting,
+    /// Too many submissions in a short time; see `retryAfter`
+    RateLimited,
+    /// The session used up its daily grading time; see `retryAfter`
+    QuotaExceeded,
+    /// The runner is deployed in a way that does not offer this endpoint, e.g. the sandbox on a coordinator
+    NotSupported,
+    InternalError,
+}
+
+impl std::fmt::Display for ErrorCode {
+    /// The serialized name, e.g. `compileError`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(name.as_str().unwrap_or_default())
+    }
+}
+
+impl ErrorCode {
+    /// HTTP status of a response with this code on `/v2`; `/v1` answers `200` regardless
+    pub fn status(self) -> http::StatusCode {
+        match self {
+            ErrorCode::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => http::StatusCode::FORBIDDEN,
+            ErrorCode::UnknownLesson => http::StatusCode::NOT_FOUND,
+            ErrorCode::InvalidSubmission => http::StatusCode::BAD_REQUEST,
+            ErrorCode::CompileError | ErrorCode::TestCompileError => http::StatusCode::UNPROCESSABLE_ENTITY,
+            // the grading itself succeeded
+            ErrorCode::TestFailure => http::StatusCode::OK,
+            ErrorCode::Timeout => http::StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::RunnerBusy | ErrorCode::RunnerRestarting => http::StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::RateLimited | ErrorCode::QuotaExceeded => http::StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::NotSupported => http::StatusCode::NOT_IMPLEMENTED,
+            ErrorCode::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct TTestResponse {
     passed: bool,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    /// Human-readable explanation of `errorCode`
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
     // gas: Option<usize>,
     tests: Vec<TTest>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<TDiagnostic>,
+    /// How the student's program was executed by the tests
+    #[serde(rename = "executionMode", skip_serializing_if = "Option::is_none")]
+    execution_mode: Option<ExecutionMode>,
+    /// The toolchain that built and tested the program
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    toolchain: Option<TToolchain>,
+    /// The same submission was graded before, and this is the remembered result
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// Seconds to wait before submitting again; also sent as the `Retry-After` header
+    #[serde(rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ExecutionMode {
+    /// The program was compiled for the host and called through `processor!`
+    Native,
+    /// The compiled `.so` was loaded into the SBF VM
+    Sbf,
+}
+
+/// A toolchain profile of the runner, with the versions of its tools
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct TToolchain {
+    pub name: String,
+    /// Versions by tool, as reported by `cargo build-sbf --version`, e.g. `platform-tools` → `v1.41`
+    pub versions: BTreeMap<String, String>,
 }
 
 impl TTestResponse {
 
     /// Create a response with an error message, explaining why the tests could not be run
-    pub fn error(error: impl ToString) -> Self {
-        Self { passed: false, error: Some(error.to_string()), tests: vec![] }
+    pub fn error(error_code: ErrorCode, error: impl ToString) -> Self {
+        Self { passed: false, error_code: Some(error_code), error: Some(error.to_string()), tests: vec![], diagnostics: vec![], execution_mode: None, toolchain: None, cached: false, retry_after: None }
+    }
+
+    /// Attach compiler (or other tool) messages related to the submitted code
+    pub fn with_diagnostics(mut self, diagnostics: Vec<TDiagnostic>) -> Self {
+        self.diagnostics.extend(diagnostics);
+        self
+    }
+
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.error_code
+    }
+
+    pub fn status(&self) -> http::StatusCode {
+        self.error_code.map_or(http::StatusCode::OK, ErrorCode::status)
+    }
+
+    pub fn with_cached(mut self) -> Self {
+        self.cached = true;
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        self.retry_after = Some(retry_after.as_secs().max(1));
+        self
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+
+    pub fn with_execution_mode(mut self, execution_mode: ExecutionMode) -> Self {
+        self.execution_mode = Some(execution_mode);
+        self
+    }
+
+    pub fn with_toolchain(mut self, toolchain: TToolchain) -> Self {
+        self.toolchain = Some(toolchain);
+        self
+    }
+
+    /// Create a response with the results of the tests, redacting the details of hidden tests
+    ///
+    /// Hidden tests still count toward `passed`, but they are reported only as "hidden check N",
+    /// numbered in the order of `hidden_tests`.
+    pub fn with_hidden(tests: Vec<TTest>, hidden_tests: &[String]) -> Self {
+        let tests = tests.into_iter()
+            .map(|test| match hidden_tests.iter().position(|hidden| hidden == &test.title) {
+                Some(index) => test.redacted(index + 1),
+                None => test,
+            })
+            .collect::<Vec<_>>();
+        Self::from(tests)
+    }
+}
+
+impl std::fmt::Display for TTestResponse {
+    /// Human-readable report, as printed by the `solve` command
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for test in &self.tests {
+            let outcome = if test.passed { "ok" } else { "FAILED" };
+            writeln!(f, "{outcome:>6}  {}", test.title)?;
+            if let Some(error) = &test.error {
+                for line in error.lines() {
+                    writeln!(f, "        {line}")?;
+                }
+            }
+            for account in test.accounts.iter().filter(|_| !test.passed) {
+                for change in &account.changes {
+                    writeln!(f, "        {}.{change}", account.name)?;
+                }
+            }
+        }
+        for diagnostic in &self.diagnostics {
+            let code = diagnostic.code.as_ref().map(|code| format!("[{code}]")).unwrap_or_default();
+            writeln!(f, "{}{code}: {}", diagnostic.severity, diagnostic.message)?;
+            if let Some(file) = &diagnostic.file {
+                let line = diagnostic.line.map(|line| format!(":{line}")).unwrap_or_default();
+                let column = diagnostic.column.map(|column| format!(":{column}")).unwrap_or_default();
+                writeln!(f, "  --> {file}{line}{column}")?;
+            }
+        }
+        match (self.error_code, &self.error) {
+            (None, _) => write!(f, "PASSED: {} tests", self.tests.len())?,
+            (Some(code), error) => write!(f, "FAILED ({code}): {}", error.as_deref().unwrap_or_default())?,
+        }
+        if self.cached {
+            write!(f, " (cached)")?;
+        }
+        Ok(())
     }
 }
 
@@ -41,32 +245,198 @@ impl From<Vec<TTest>> for TTestResponse {
     /// Note that top-level `passed` is set to `true` only if all tests passed; and, there must be at least one
     fn from(tests: Vec<TTest>) -> Self {
         if tests.is_empty() {
-            return Self::error("No tests executed.");
+            return Self::error(ErrorCode::InternalError, "No tests executed.");
         }
         let failed_count = tests.iter()
             .filter(|test| !test.passed)
             .count();
         let passed = failed_count == 0;
         let error = if passed { None } else { Some(format!("{} of {} tests failed", failed_count, tests.len())) };
-        Self { passed, error, tests }
+        let error_code = (!passed).then_some(ErrorCode::TestFailure);
+        Self { passed, error_code, error, tests, diagnostics: vec![], execution_mode: None, toolchain: None, cached: false, retry_after: None }
     }
 }
 
-#[derive(Debug,Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct TTest {
     pub title: String,
     passed: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// How the accounts used by the test changed; only lessons graded with scenarios (`scenarios.json`) report it,
+    /// lessons with Rust `lesson_tests` never do
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    accounts: Vec<TAccountDiff>,
 }
 
 impl TTest {
     pub fn error(title: impl ToString, error: impl ToString) -> Self {
-        Self { title: title.to_string(), passed: false, error: Some(error.to_string()) }
+        Self { title: title.to_string(), passed: false, error: Some(error.to_string()), accounts: vec![] }
     }
 
     pub fn ok(title: impl ToString) -> Self {
-        Self { title: title.to_string(), passed: true, error: None }
+        Self { title: title.to_string(), passed: true, error: None, accounts: vec![] }
+    }
+
+    pub fn with_accounts(mut self, accounts: Vec<TAccountDiff>) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    /// Replace the title and failure details, keeping only the outcome
+    pub fn redacted(self, ordinal: usize) -> Self {
+        let title = format!("hidden check {ordinal}");
+        let error = (!self.passed).then(|| format!("{title} failed"));
+        Self { title, passed: self.passed, error, accounts: vec![] }
+    }
+}
+
+/// The fields of an account that changed during a test, or that the test expects a value of
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct TAccountDiff {
+    /// Name of the account in the scenario, or its address
+    pub name: String,
+    pub address: String,
+    pub changes: Vec<TFieldChange>,
+}
+
+/// Values of a field before and after a test, formatted for display; missing when the account does not exist
+///
+/// Besides `lamports`, `owner`, `dataLen` and byte ranges of `data` like `data[8..16]`, the fields of known data
+/// layouts are decoded, e.g. `token.amount` of an SPL token account, or `mint.supply` of an SPL mint.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct TFieldChange {
+    pub field: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    /// What the test expects the value after to be
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+impl std::fmt::Display for TFieldChange {
+    /// E.g. `lamports: 5 → 5 (expected 0)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = |value: &Option<String>| value.clone().unwrap_or_else(|| "none".to_string());
+        write!(f, "{}: {} → {}", self.field, value(&self.before), value(&self.after))?;
+        if let Some(expected) = &self.expected {
+            write!(f, " (expected {expected})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Transactions to run against the submitted program, to see what it does; nothing is graded
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct TSandboxRequest {
+    /// The program to build, as for `/v2/solve`
+    #[serde(flatten)]
+    pub submission: TTestRequest,
+    /// Base58 address of the program; by default the `declare_id!` of the submitted code, or the one of the lesson
+    #[serde(rename = "programId", skip_serializing_if = "Option::is_none")]
+    pub program_id: Option<String>,
+    /// Accounts that exist before the first transaction, by name, as in lesson scenarios
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountSpec>,
+    /// Sent one by one, also after one fails; their expectations are ignored
+    pub transactions: Vec<TransactionSpec>,
+}
+
+/// What the transactions of a `TSandboxRequest` did
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct TSandboxResponse {
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    /// Human-readable explanation of `errorCode`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<TDiagnostic>,
+    /// Base58 addresses of the named accounts, including `program`, `payer` and `system`
+    #[serde(default)]
+    addresses: BTreeMap<String, String>,
+    #[serde(default)]
+    transactions: Vec<TransactionOutcome>,
+    /// State of the declared accounts and the payer after the last transaction; `null` when the account does not exist
+    #[serde(default)]
+    #[schema(value_type = BTreeMap<String, AccountState>)]
+    accounts: BTreeMap<String, Option<AccountState>>,
+    /// Seconds to wait before sending again; also sent as the `Retry-After` header
+    #[serde(rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+impl TSandboxResponse {
+    /// Create a response with an error message, explaining why the transactions could not be run
+    pub fn error(error_code: ErrorCode, error: impl ToString) -> Self {
+        Self { error_code: Some(error_code), error: Some(error.to_string()), ..Default::default() }
+    }
+
+    pub fn with_diagnostics(mut self, diagnostics: Vec<TDiagnostic>) -> Self {
+        self.diagnostics.extend(diagnostics);
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        self.retry_after = Some(retry_after.as_secs().max(1));
+        self
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+
+    pub fn status(&self) -> http::StatusCode {
+        self.error_code.map_or(http::StatusCode::OK, ErrorCode::status)
+    }
+}
+
+impl From<Outcome> for TSandboxResponse {
+    /// A scenario that could not be set up, e.g. because an account is invalid, is the sender's mistake
+    fn from(outcome: Outcome) -> Self {
+        if let Some(error) = outcome.error {
+            return Self::error(ErrorCode::InvalidSubmission, error);
+        }
+        Self { addresses: outcome.addresses, transactions: outcome.transactions, accounts: outcome.accounts, ..Default::default() }
+    }
+}
+
+/// A message from the compiler or another tool, optionally pointing at a location in the submitted code
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct TDiagnostic {
+    severity: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<u32>,
+    /// Name of the lint, for warnings of the static analysis, e.g. `missing-signer-check`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl TDiagnostic {
+    pub fn new(severity: impl ToString, message: impl ToString) -> Self {
+        Self { severity: severity.to_string(), message: message.to_string(), file: None, line: None, column: None, code: None }
+    }
+
+    pub fn with_code(mut self, code: impl ToString) -> Self {
+        self.code = Some(code.to_string());
+        self
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn set_location(&mut self, file: impl ToString, line: Option<u32>, column: Option<u32>) {
+        self.file = Some(file.to_string());
+        self.line = line;
+        self.column = column;
     }
 }
 
@@ -77,14 +447,13 @@ mod tests {
     #[test]
     fn de_solve_request() {
         let body = r##"{
-    "runner": "docker-runner",
-    "image": "rbiosas/nearjs-docker-runner",
+    "runner": "solana",
     "type": "course",
-    "courseSlug": "introduction-to-nearjs",
-    "lessonSlug": "04-environment",
+    "courseSlug": "intro-to-solana",
+    "lessonSlug": "sysvar",
     "files": [
         {
-            "path": "contract.ts",
+            "path": "lib.rs",
             "content": "// your code here\n"
         }
     ]
@@ -94,26 +463,34 @@ mod tests {
         let parsed = format!("{parsed:?}");
         println!("parsed: {parsed}");
 
-        assert_eq!(parsed, r##"TTestRequest { runner: "docker-runner", type: Some("course"), course_slug: "introduction-to-nearjs", lesson_slug: "04-environment", files: [TEditorFile { path: "contract.ts", content: "// your code here\n" }], image: Some("rbiosas/nearjs-docker-runner") }"##)
+        assert_eq!(parsed, r##"TTestRequest { runner: "solana", type: Some("course"), course_slug: "intro-to-solana", lesson_slug: "sysvar", files: [TEditorFile { path: "lib.rs", content: "// your code here\n" }], image: None }"##)
     }
 
     #[test]
     fn ser_solve_response() {
         let response = TTestResponse {
             passed: false,
+            error_code: None,
             error: None,
             tests: vec![
                 TTest {
                     title: "HI! Method `increment` should increment the user's personal counter".to_string(),
                     passed: true,
                     error: None,
+                    accounts: vec![],
                 },
                 TTest {
                     title: "HI! Method `get_value` should accept accountId parameter".to_string(),
                     passed: false,
                     error: Some("expected 3 to equal 1".to_string()),
+                    accounts: vec![],
                 },
             ],
+            diagnostics: vec![],
+            execution_mode: None,
+            toolchain: None,
+            cached: false,
+            retry_after: None,
         };
         let serialized = serde_json::to_string_pretty(&response).unwrap();
         println!("serialized: {serialized}");
@@ -133,4 +510,60 @@ mod tests {
 }"##, serialized);
 
     }
+
+    #[test]
+    fn error_codes() {
+        let response = TTestResponse::error(ErrorCode::CompileError, "Failed to compile code; exit code = 101");
+        assert_eq!(response.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert_eq!(r##"{"passed":false,"errorCode":"compileError","error":"Failed to compile code; exit code = 101","tests":[]}"##, serialized);
+
+        let response = TTestResponse::from(vec![TTest::ok("test_sysvar")]);
+        assert_eq!(response.error_code(), None);
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn validate_submitted_paths() {
+        let request = |path: &str| TTestRequest {
+            runner: "solana".to_string(),
+            r#type: None,
+            course_slug: "intro-to-solana".to_string(),
+            lesson_slug: "sysvar".to_string(),
+            files: vec![TEditorFile { path: path.to_string(), content: String::new() }],
+            image: None,
+        };
+        assert_eq!(request("lib.rs").validate(), Ok(()));
+        assert_eq!(request("instructions/mod.rs").validate(), Ok(()));
+        assert!(request("../Cargo.toml").validate().is_err());
+        assert!(request("/etc/passwd").validate().is_err());
+        assert!(request("").validate().is_err());
+    }
+
+    #[test]
+    fn hidden_tests_are_redacted() {
+        let tests = vec![
+            TTest::ok("test_visible"),
+            TTest::error("test_secret_b", "assertion `left == right` failed\n  left: 5\n right: 0"),
+            TTest::ok("test_secret_a"),
+        ];
+        let hidden = vec!["test_secret_a".to_string(), "test_secret_b".to_string()];
+        let response = TTestResponse::with_hidden(tests, &hidden);
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert_eq!(r##"{"passed":false,"errorCode":"testFailure","error":"1 of 3 tests failed","tests":[{"title":"test_visible","passed":true},{"title":"hidden check 2","passed":false,"error":"hidden check 2 failed"},{"title":"hidden check 1","passed":true}]}"##, serialized);
+    }
+
+    #[test]
+    fn human_readable_report() {
+        let response = TTestResponse::from(vec![
+            TTest::ok("test_sysvar"),
+            TTest::error("test_clock", "assertion failed\n  left: 5"),
+        ]);
+        assert_eq!(response.to_string(), "    ok  test_sysvar\nFAILED  test_clock\n        assertion failed\n          left: 5\nFAILED (testFailure): 1 of 2 tests failed");
+
+        let mut diagnostic = TDiagnostic::new("error", "cannot find value `x` in this scope");
+        diagnostic.set_location("lib.rs", Some(3), Some(5));
+        let response = TTestResponse::error(ErrorCode::CompileError, "Failed to compile code").with_diagnostics(vec![diagnostic]);
+        assert_eq!(response.to_string(), "error: cannot find value `x` in this scope\n  --> lib.rs:3:5\nFAILED (compileError): Failed to compile code");
+    }
 }
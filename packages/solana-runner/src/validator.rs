@@ -0,0 +1,138 @@
+//! A local `solana-test-validator` for a job, serving the lesson tests of the `Validator` test mode over RPC
+//!
+//! Every job gets a fresh validator with the submitted program deployed at genesis, and it is killed when the
+//! `Validator` is dropped. It listens on free ports of the loopback interface, and needs no network otherwise.
+//! When it does not start, the ends of its output and of its log explain why, in the error; the ledger with the log is
+//! removed with the `Validator` all the same.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::process::{Child, Command};
+
+/// Lines of the output and of the log of a validator that failed to start, quoted in the error
+const TAIL_LINES: usize = 20;
+
+lazy_static::lazy_static!(
+    /// The validator must answer RPC within this long after it was started
+    static ref STARTUP_TIMEOUT: Duration = Duration::from_secs(
+        std::env::var("AGORA_VALIDATOR_STARTUP_SECS").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(60)
+    );
+);
+
+pub struct Validator {
+    /// JSON RPC endpoint
+    pub rpc_url: String,
+    /// `None` when replayed
+    child: Option<Child>,
+    ledger: Option<PathBuf>,
+}
+
+impl Validator {
+    /// Start `cmd`, the `solana-test-validator` of the toolchain, with the program `program_so` deployed at
+    /// `program_id`, and wait until it is healthy; the ledger is kept in `dir`
+    pub async fn start(mut cmd: Command, dir: &Path, program_id: &str, program_so: &Path) -> anyhow::Result<Self> {
+        let ledger = dir.join("target/validator-ledger");
+        let rpc_port = free_port()?;
+        cmd.arg("--ledger").arg(&ledger)
+            .args(["--reset", "--quiet", "--bind-address", "127.0.0.1"])
+            .args(["--rpc-port", &rpc_port.to_string()])
+            .args(["--faucet-port", &free_port()?.to_string()])
+            .args(["--bpf-program", program_id]).arg(program_so)
+            .current_dir(dir)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true);
+        // next to the ledger, which the validator may not get to create
+        let output = dir.join("target/validator-output.log");
+        let file = std::fs::File::create(&output)?;
+        cmd.stdout(file.try_clone()?).stderr(file);
+        tracing::debug!("Executing: {:?}", cmd);
+        let child = cmd.spawn()?;
+        let mut validator = Self { rpc_url: format!("http://127.0.0.1:{rpc_port}"), child: Some(child), ledger: Some(ledger) };
+        if let Err(err) = validator.wait_until_healthy().await {
+            let mut message = err.to_string();
+            for (name, path) in [("Output", output), ("Log", validator.log())] {
+                let tail = tail(&path);
+                if !tail.is_empty() {
+                    message.push_str(&format!("\n{name}:\n{tail}"));
+                }
+            }
+            anyhow::bail!(message);
+        }
+        tracing::info!("Validator is listening on {}", validator.rpc_url);
+        Ok(validator)
+    }
+
+    /// A validator that only pretends to run, for `backend::Replay`
+    pub fn replayed() -> Self {
+        Self { rpc_url: "http://127.0.0.1:8899".to_string(), child: None, ledger: None }
+    }
+
+    async fn wait_until_healthy(&mut self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let client = reqwest::Client::new();
+        let request = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getHealth" });
+        loop {
+            if let Some(status) = self.child.as_mut().map(Child::try_wait).transpose()?.flatten() {
+                anyhow::bail!("The validator exited on startup ({status})");
+            }
+            let health = client.post(&self.rpc_url).json(&request).timeout(Duration::from_secs(1)).send().await;
+            if let Ok(response) = health {
+                let body: serde_json::Value = response.json().await.unwrap_or_default();
+                if body["result"] == "ok" {
+                    return Ok(());
+                }
+            }
+            if start.elapsed() > *STARTUP_TIMEOUT {
+                anyhow::bail!("The validator did not become healthy in {} seconds", STARTUP_TIMEOUT.as_secs());
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+
+    fn log(&self) -> PathBuf {
+        self.ledger.as_deref().unwrap_or(Path::new("")).join("validator.log")
+    }
+}
+
+impl Drop for Validator {
+    fn drop(&mut self) {
+        // the child is killed on drop; its ledger is not needed anymore, and takes hundreds of megabytes
+        if let Some(ledger) = &self.ledger {
+            let _ = std::fs::remove_dir_all(ledger);
+        }
+    }
+}
+
+/// The last `TAIL_LINES` lines of a file; empty when it cannot be read
+fn tail(path: &Path) -> String {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    let lines = content.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(TAIL_LINES)..].join("\n")
+}
+
+/// A port that is free on the loopback interface right now
+fn free_port() -> anyhow::Result<u16> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn startup_failure() {
+        let dir = std::env::temp_dir().join(format!("agora-validator-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        // the arguments of the validator follow the script, as `$0`, `$1`, ...
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'Error: failed to bind the RPC port' >&2; exit 3"]);
+        let err = Validator::start(cmd, &dir, "Counter111111111111111111111111111111111111", Path::new("counter.so")).await.err().unwrap();
+        let message = err.to_string();
+        assert!(message.starts_with("The validator exited on startup (exit status: 3)"), "{message}");
+        assert!(message.contains("Output:\nError: failed to bind the RPC port"), "{message}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Worker process, grading the jobs of a coordinator (`AGORA_MODE=worker`); see `coordinator.rs`
+//!
+//! The worker connects to `AGORA_COORDINATOR_URL`, and authenticates with `AGORA_AUTH_TOKEN` when it is set.
+//! It grades at most `AGORA_MAX_JOBS` submissions at once, like a standalone runner, each in the working directory of
+//! its job slot. Unless `AGORA_WORK_DIR` is set, the working directories are under one of this process
+//! (`agora-worker-<pid>` in the temporary directory), so that several workers can run on one machine.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::Instrument;
+
+use crate::coordinator::{JobAssignment, JobResult, Registered, Registration, HEARTBEAT_INTERVAL, POLL_TIMEOUT};
+
+#[derive(Clone)]
+struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.request(method, format!("{}{path}", self.base_url));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn register(&self, registration: &Registration) -> anyhow::Result<String> {
+        let response = self.request(reqwest::Method::POST, "/internal/workers")
+            .json(registration)
+            .send().await?
+            .error_for_status()?;
+        Ok(response.json::<Registered>().await?.worker_id)
+    }
+
+    /// `false` when the coordinator forgot the worker
+    async fn heartbeat(&self, worker_id: &str) -> anyhow::Result<bool> {
+        let response = self.request(reqwest::Method::POST, &format!("/internal/workers/{worker_id}/heartbeat"))
+            .send().await?;
+        if response.status() == http::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// `Err` with `StatusCode::NOT_FOUND` when the coordinator forgot the worker
+    async fn next_job(&self, worker_id: &str) -> anyhow::Result<Option<JobAssignment>> {
+        let response = self.request(reqwest::Method::GET, &format!("/internal/workers/{worker_id}/jobs/next"))
+            .timeout(POLL_TIMEOUT + Duration::from_secs(10))
+            .send().await?
+            .error_for_status()?;
+        if response.status() == http::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    async fn complete(&self, worker_id: &str, job_id: u64, result: &JobResult) -> anyhow::Result<()> {
+        self.request(reqwest::Method::POST, &format!("/internal/workers/{worker_id}/jobs/{job_id}/result"))
+            .json(result)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn deregister(&self, worker_id: &str) -> anyhow::Result<()> {
+        self.request(reqwest::Method::DELETE, &format!("/internal/workers/{worker_id}"))
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Grade jobs until the runner starts shutting down, and the jobs in progress are finished
+pub async fn run(coordinator_url: &str) -> anyhow::Result<()> {
+    let client = Client {
+        http: reqwest::Client::new(),
+        base_url: coordinator_url.trim_end_matches('/').to_string(),
+        token: std::env::var("AGORA_AUTH_TOKEN").ok().filter(|token| !token.is_empty()),
+    };
+    let registration = Registration {
+        toolchains: crate::TOOLCHAINS.profiles.iter().map(|profile| profile.name.clone()).collect(),
+        slots: *crate::MAX_JOBS,
+    };
+    while !crate::shutdown::is_draining() {
+        let worker_id = match client.register(&registration).await {
+            Ok(worker_id) => worker_id,
+            Err(err) => {
+                tracing::warn!("Cannot register with the coordinator at {coordinator_url}: {err}");
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                continue;
+            }
+        };
+        tracing::info!("Registered as worker {worker_id}");
+        // each slot pulls its own jobs; they all stop when the coordinator forgets the worker
+        let forgotten = std::sync::Arc::new(tokio::sync::watch::channel(false).0);
+        let mut slots = tokio::task::JoinSet::new();
+        for _ in 0..registration.slots {
+            slots.spawn(pull_jobs(client.clone(), worker_id.clone(), forgotten.clone()));
+        }
+        tokio::select! {
+            _ = async { while slots.join_next().await.is_some() {} } => {},
+            _ = send_heartbeats(&client, &worker_id, &forgotten) => {},
+        }
+        if crate::shutdown::is_draining() {
+            if let Err(err) = client.deregister(&worker_id).await {
+                tracing::warn!("Cannot deregister: {err}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send_heartbeats(client: &Client, worker_id: &str, forgotten: &tokio::sync::watch::Sender<bool>) {
+    loop {
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        match client.heartbeat(worker_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!("The coordinator forgot this worker, registering again");
+                forgotten.send_replace(true);
+            }
+            Err(err) => tracing::warn!("Heartbeat failed: {err}"),
+        }
+    }
+}
+
+async fn pull_jobs(client: Client, worker_id: String, forgotten: std::sync::Arc<tokio::sync::watch::Sender<bool>>) {
+    while !crate::shutdown::is_draining() && !*forgotten.borrow() {
+        let next_job = tokio::select! {
+            next_job = client.next_job(&worker_id) => next_job,
+            _ = crate::shutdown::draining() => break,
+        };
+        let job = match next_job {
+            Ok(Some(job)) => job,
+            Ok(None) => continue,
+            Err(err) => {
+                let unknown = err.downcast_ref::<reqwest::Error>()
+                    .and_then(reqwest::Error::status) == Some(http::StatusCode::NOT_FOUND);
+                if unknown {
+                    forgotten.send_replace(true);
+                } else {
+                    tracing::warn!("Cannot get the next job: {err}");
+                    tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                }
+                continue;
+            }
+        };
+        let span = tracing::info_span!("job", job_id = job.job_id, lesson = %job.request.lesson_slug);
+        let result = grade(job.request).instrument(span).await;
+        if let Err(err) = client.complete(&worker_id, job.job_id, &result).await.context("Cannot send the result") {
+            tracing::error!("Job {}: {err:#}", job.job_id);
+        }
+    }
+}
+
+async fn grade(request: crate::types::TTestRequest) -> JobResult {
+    let (response, timings) = crate::solve_raw(request).await;
+    let timings = timings.into_iter()
+        .map(|(phase, duration)| (phase, duration.as_millis() as u64))
+        .collect();
+    JobResult { response, timings }
+}
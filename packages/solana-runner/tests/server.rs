@@ -0,0 +1,214 @@
+//! The runner over HTTP, grading with recorded tool output instead of a Solana toolchain
+//!
+//! Every test starts its own server with the replay profiles of `tests/toolchains.json`; a request picks the
+//! recording by naming its profile as the `runner`.
+
+use std::io::BufRead;
+use std::process::{Child, Command, Stdio};
+
+use serde_json::{json, Value};
+
+const TOKEN: &str = "integration-test";
+
+struct Server {
+    child: Child,
+    url: String,
+    work_dir: std::path::PathBuf,
+}
+
+impl Server {
+    fn start(name: &str, env: &[(&str, &str)]) -> Self {
+        let work_dir = std::env::temp_dir().join(format!("agora-server-{name}-{}", std::process::id()));
+        let mut child = Command::new(env!("CARGO_BIN_EXE_agorapp-solana"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .env("AGORA_LISTEN", "127.0.0.1:0")
+            .env("AGORA_LOG_FORMAT", "json")
+            .env("AGORA_TOOLCHAINS", "tests/toolchains.json")
+            .env("AGORA_WORK_DIR", &work_dir)
+            .env("AGORA_AUTH_TOKEN", TOKEN)
+            .envs(env.iter().copied())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        let address = lines.by_ref()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                let entry: Value = serde_json::from_str(&line).ok()?;
+                entry["fields"]["message"].as_str()?.strip_prefix("Listening on ").map(str::to_string)
+            })
+            .expect("the server starts listening");
+        // keep reading the logs, so that the server does not block on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        Self { child, url: format!("http://{address}"), work_dir }
+    }
+
+    async fn solve(&self, runner: &str, lesson_slug: &str, token: Option<&str>) -> (reqwest::StatusCode, Value) {
+        let body = json!({
+            "runner": runner,
+            "courseSlug": "intro-to-solana",
+            "lessonSlug": lesson_slug,
+            "files": [{ "path": "lib.rs", "content": "// submitted" }],
+        });
+        self.post("/v2/solve", &body, token).await
+    }
+
+    async fn post(&self, path: &str, body: &Value, token: Option<&str>) -> (reqwest::StatusCode, Value) {
+        self.post_in_session(path, body, token, None).await
+    }
+
+    async fn post_in_session(&self, path: &str, body: &Value, token: Option<&str>, session_id: Option<&str>) -> (reqwest::StatusCode, Value) {
+        let mut request = reqwest::Client::new().post(format!("{}{path}", self.url)).json(body);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(session_id) = session_id {
+            request = request.header("x-session-id", session_id);
+        }
+        let response = request.send().await.unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.work_dir);
+    }
+}
+
+#[tokio::test]
+async fn solve_with_recorded_results() {
+    let server = Server::start("results", &[]);
+
+    let (status, response) = server.solve("replay-passed", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["passed"], true);
+    assert_eq!(response["executionMode"], "sbf");
+    assert_eq!(response["toolchain"]["name"], "replay-passed");
+    assert_eq!(response["toolchain"]["versions"]["platform-tools"], "v1.41");
+    // `test_lamport_balances` is hidden by the lesson
+    assert_eq!(response["tests"], json!([
+        { "title": "hidden check 1", "passed": true },
+        { "title": "test_lamport_transfer", "passed": true },
+    ]));
+    let (_, response) = server.solve("replay-passed", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(response["cached"], true);
+
+    let (status, response) = server.solve("replay-failed", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["errorCode"], "testFailure");
+    assert_eq!(response["tests"][1]["error"], "assertion `left == right` failed: Recipient did not receive the lamports\n");
+
+    let toolchains: Value = reqwest::get(format!("{}/v1/status/toolchains", server.url)).await.unwrap().json().await.unwrap();
+    assert_eq!(toolchains[0]["name"], "replay-passed");
+    assert_eq!(toolchains[0]["versions"]["rustc"], "1.75.0");
+}
+
+#[tokio::test]
+async fn solve_errors() {
+    let server = Server::start("errors", &[]);
+
+    let (status, response) = server.solve("replay-compile-error", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 422);
+    assert_eq!(response["errorCode"], "compileError");
+    assert_eq!(response["diagnostics"][0]["line"], 31);
+
+    let (status, response) = server.solve("replay-test-compile-error", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 422);
+    assert_eq!(response["errorCode"], "testCompileError");
+
+    let (status, response) = server.solve("replay-passed", "02-transfer-lamports", None).await;
+    assert_eq!(status, 401);
+    assert_eq!(response["errorCode"], "unauthorized");
+
+    let (status, response) = server.solve("replay-passed", "no-such-lesson", Some(TOKEN)).await;
+    assert_eq!(status, 404);
+    assert_eq!(response["errorCode"], "unknownLesson");
+
+    let (status, response) = server.solve("docker-runner", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 400);
+    assert_eq!(response["errorCode"], "invalidSubmission");
+
+    // the first version of the protocol tells errors by the `errorCode` only
+    let body = json!({
+        "runner": "replay-compile-error",
+        "courseSlug": "intro-to-solana",
+        "lessonSlug": "02-transfer-lamports",
+        "files": [{ "path": "lib.rs", "content": "// submitted" }],
+    });
+    let (status, response) = server.post("/v1/solve", &body, Some(TOKEN)).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["errorCode"], "compileError");
+}
+
+#[tokio::test]
+async fn solve_timeout() {
+    let server = Server::start("timeout", &[("AGORA_JOB_TIMEOUT_SECS", "1")]);
+
+    let (status, response) = server.solve("replay-slow", "02-transfer-lamports", Some(TOKEN)).await;
+    assert_eq!(status, 504);
+    assert_eq!(response["errorCode"], "timeout");
+}
+
+#[tokio::test]
+async fn session_limits() {
+    let body = json!({
+        "runner": "replay-passed",
+        "courseSlug": "intro-to-solana",
+        "lessonSlug": "02-transfer-lamports",
+        "files": [{ "path": "lib.rs", "content": "// submitted" }],
+    });
+    let limited = [("AGORA_RATE_SESSION_PER_MINUTE", "1")];
+
+    // without a session, a request is limited by its client address
+    let server = Server::start("limits", &limited);
+    let (status, _) = server.post("/v2/solve", &body, Some(TOKEN)).await;
+    assert_eq!(status, 200);
+    let (status, response) = server.post("/v2/solve", &body, Some(TOKEN)).await;
+    assert_eq!(status, 429);
+    assert_eq!(response["errorCode"], "rateLimited");
+    // the sessions our services vouch for have limits of their own
+    let (status, _) = server.post_in_session("/v2/solve", &body, Some(TOKEN), Some("s1")).await;
+    assert_eq!(status, 200);
+
+    // without authentication, changing the session does not reset the limits
+    let server = Server::start("limits-unauthenticated", &[limited[0], ("AGORA_AUTH_TOKEN", "")]);
+    let (status, _) = server.post_in_session("/v2/solve", &body, None, Some("s1")).await;
+    assert_eq!(status, 200);
+    let (status, response) = server.post_in_session("/v2/solve", &body, None, Some("s2")).await;
+    assert_eq!(status, 429);
+    assert_eq!(response["errorCode"], "rateLimited");
+}
+
+#[tokio::test]
+async fn sandbox_invoke() {
+    let server = Server::start("sandbox", &[]);
+    let mut body = json!({
+        "runner": "replay-scenarios",
+        "courseSlug": "intro-to-solana",
+        "lessonSlug": "01-introduction",
+        "files": [{ "path": "lib.rs", "content": "// submitted" }],
+        "accounts": { "account": {} },
+        "transactions": [{ "instructions": [{ "accounts": [{ "account": "account", "writable": true }], "data": "01" }] }],
+    });
+
+    let (status, response) = server.post("/v2/sandbox", &body, Some(TOKEN)).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["transactions"][0]["logs"][1], "Program log: Hello, Solana!");
+    assert_eq!(response["transactions"][0]["computeUnits"], 137);
+    assert_eq!(response["addresses"]["program"], "Logging111111111111111111111111111111111111");
+    assert!(response.get("tests").is_none());
+
+    body["transactions"][0]["instructions"][0]["accounts"][0]["account"] = json!("recipient");
+    let (status, response) = server.post("/v2/sandbox", &body, Some(TOKEN)).await;
+    assert_eq!(status, 400);
+    assert_eq!(response["errorCode"], "invalidSubmission");
+    assert_eq!(response["error"], r#"Scenario "sandbox": unknown account "recipient""#);
+
+    let coordinator = Server::start("sandbox-coordinator", &[("AGORA_MODE", "coordinator")]);
+    let (status, response) = coordinator.post("/v2/sandbox", &body, Some(TOKEN)).await;
+    assert_eq!(status, 501);
+    assert_eq!(response["errorCode"], "notSupported");
+}